@@ -0,0 +1,703 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::commands::{self, CommandSpec, KeyAccess};
+use crate::error::CommandError;
+use crate::glob::glob_match;
+use crate::resp::RespType;
+use crate::sha256::sha256_hex;
+
+pub(crate) const CATEGORIES: [&str; 21] = [
+    "keyspace", "read", "write", "set", "sortedset", "list", "hash", "string", "bitmap", "hyperloglog", "geo", "stream",
+    "pubsub", "admin", "fast", "slow", "blocking", "dangerous", "connection", "transaction", "scripting",
+];
+
+/// Maximum number of entries kept by `ACL LOG`
+const ACL_LOG_MAX_LEN: usize = 128;
+/// Denials of the same kind within this time are grouped into a single `ACL LOG` entry
+const ACL_LOG_GROUPING_MAX_TIME_DELTA: Duration = Duration::from_secs(60);
+
+/// Channels accessed by the command, patterns of `PSUBSCRIBE` must match the allowed ones literally
+fn command_channels<'a>(command: &[u8], args: &'a VecDeque<RespType>) -> (Vec<&'a [u8]>, bool) {
+    let channels = args.iter().filter_map(|arg| arg.as_str_bytes().ok());
+    match command {
+        b"publish" | b"spublish" => (channels.take(1).collect(), false),
+        b"subscribe" | b"ssubscribe" => (channels.collect(), false),
+        b"psubscribe" => (channels.collect(), true),
+        _ => (Vec::new(), false),
+    }
+}
+
+/// Command name as used in ACL rules, including the subcommand of container commands
+pub(crate) fn full_command_name(command: &CommandSpec, args: &VecDeque<RespType>) -> String {
+    if !command.is_container() {
+        return command.name.to_owned();
+    }
+    match args.front().and_then(|arg| arg.as_str_bytes().ok()) {
+        Some(subcommand) => format!("{}|{}", command.name, String::from_utf8_lossy(subcommand).to_ascii_lowercase()),
+        None => command.name.to_owned(),
+    }
+}
+
+/// Reason of the denied command, as reported by `ACL LOG`
+#[derive(Debug)]
+pub(crate) enum Denial {
+    Command(String),
+    Key(Box<[u8]>),
+    Channel(Box<[u8]>),
+    Auth,
+}
+
+impl Denial {
+    /// Error reply sent to the client
+    pub fn error(&self, username: &str) -> CommandError {
+        match self {
+            Self::Command(command) => {
+                CommandError::NoPerm(format!("User {username} has no permissions to run the '{command}' command"))
+            }
+            Self::Key(_) => CommandError::NoPerm(String::from("No permissions to access a key")),
+            Self::Channel(_) => CommandError::NoPerm(String::from("No permissions to access a channel")),
+            Self::Auth => CommandError::WrongPass,
+        }
+    }
+
+    /// Explanation returned by `ACL DRYRUN`
+    pub fn explain(&self, username: &str) -> String {
+        match self {
+            Self::Command(command) => format!("User {username} has no permissions to run the '{command}' command"),
+            Self::Key(key) => format!("User {username} has no permissions to access the '{}' key", String::from_utf8_lossy(key)),
+            Self::Channel(channel) => {
+                format!("User {username} has no permissions to access the '{}' channel", String::from_utf8_lossy(channel))
+            }
+            Self::Auth => String::from("Authentication failed"),
+        }
+    }
+
+    fn reason_and_object(&self) -> (&'static str, String) {
+        match self {
+            Self::Command(command) => ("command", command.clone()),
+            Self::Key(key) => ("key", String::from_utf8_lossy(key).into_owned()),
+            Self::Channel(channel) => ("channel", String::from_utf8_lossy(channel).into_owned()),
+            Self::Auth => ("auth", String::from("AUTH")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, false) => format!("%R~{}", self.pattern),
+            (false, true) => format!("%W~{}", self.pattern),
+            _ => format!("~{}", self.pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    /// SHA-256 of the passwords as lowercase hex
+    passwords: Vec<String>,
+    allowed_commands: HashSet<&'static str>,
+    allowed_subcommands: HashSet<String>,
+    denied_subcommands: HashSet<String>,
+    /// Command rules in the order they were applied, `+@all` and `-@all` start over
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+fn is_password_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl User {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            allowed_commands: HashSet::new(),
+            allowed_subcommands: HashSet::new(),
+            denied_subcommands: HashSet::new(),
+            command_rules: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    /// The `default` user of fresh server, allowed to do anything without password
+    fn new_default() -> Self {
+        let mut user = Self::new("default");
+        for rule in ["on", "nopass", "~*", "&*", "+@all"] {
+            user.apply_rule(rule).expect("Rules of default user are valid");
+        }
+        user
+    }
+
+    /// Applies a single `ACL SETUSER` rule
+    fn apply_rule(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![KeyPattern { pattern: String::from("*"), read: true, write: true }],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec![String::from("*")],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply_rule("+@all"),
+            "nocommands" => return self.apply_rule("-@all"),
+            "reset" => *self = Self::new(&self.name),
+            _ => {
+                let (prefix, value) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+                match prefix {
+                    ">" => {
+                        let hash = sha256_hex(value.as_bytes());
+                        if !self.passwords.contains(&hash) {
+                            self.passwords.push(hash);
+                        }
+                        self.nopass = false;
+                    }
+                    "#" => {
+                        if !is_password_hash(value) {
+                            return Err(String::from(
+                                "The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters",
+                            ));
+                        }
+                        if !self.passwords.iter().any(|p| p == value) {
+                            self.passwords.push(value.to_owned());
+                        }
+                        self.nopass = false;
+                    }
+                    "<" | "!" => {
+                        let hash = if prefix == "<" { sha256_hex(value.as_bytes()) } else { value.to_owned() };
+                        let count = self.passwords.len();
+                        self.passwords.retain(|p| *p != hash);
+                        if self.passwords.len() == count {
+                            return Err(String::from("The password you are trying to remove from the user does not exist"));
+                        }
+                    }
+                    "~" => self.keys.push(KeyPattern { pattern: value.to_owned(), read: true, write: true }),
+                    "%" => {
+                        let (flags, pattern) = value.split_once('~').ok_or_else(|| String::from("Syntax error"))?;
+                        let mut key = KeyPattern { pattern: pattern.to_owned(), read: false, write: false };
+                        for flag in flags.chars() {
+                            match flag.to_ascii_uppercase() {
+                                'R' => key.read = true,
+                                'W' => key.write = true,
+                                _ => return Err(String::from("Syntax error")),
+                            }
+                        }
+                        if !key.read && !key.write {
+                            return Err(String::from("Syntax error"));
+                        }
+                        self.keys.push(key);
+                    }
+                    "&" => self.channels.push(value.to_owned()),
+                    "+" | "-" => self.apply_command_rule(&rule.to_ascii_lowercase())?,
+                    _ => return Err(String::from("Syntax error")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_command(&mut self, command: &'static str, allow: bool) {
+        if allow {
+            self.allowed_commands.insert(command);
+        } else {
+            self.allowed_commands.remove(command);
+        }
+        let prefix = format!("{command}|");
+        self.allowed_subcommands.retain(|sub| !sub.starts_with(&prefix));
+        self.denied_subcommands.retain(|sub| !sub.starts_with(&prefix));
+    }
+
+    /// Applies `+command`, `-command`, `+command|subcommand` or `+@category` (and their `-` forms)
+    fn apply_command_rule(&mut self, rule: &str) -> Result<(), String> {
+        let allow = rule.starts_with('+');
+        let name = &rule[1..];
+        let unknown = || String::from("Unknown command or category name in ACL");
+        if name == "@all" {
+            self.allowed_commands = if allow { commands::COMMANDS.iter().map(|spec| spec.name).collect() } else { HashSet::new() };
+            self.allowed_subcommands.clear();
+            self.denied_subcommands.clear();
+            self.command_rules = vec![rule.to_owned()];
+            return Ok(());
+        }
+        if let Some(category) = name.strip_prefix('@') {
+            if !CATEGORIES.contains(&category) {
+                return Err(unknown());
+            }
+            // Containers are covered by the categories of their subcommands
+            for spec in commands::all_commands().filter(|spec| !spec.is_container()) {
+                if !spec.categories.contains(&category) {
+                    continue;
+                }
+                if spec.name.contains('|') {
+                    self.set_subcommand(spec.name, allow);
+                } else {
+                    self.set_command(spec.name, allow);
+                }
+            }
+        } else if let Some((command, subcommand)) = name.split_once('|') {
+            let container = commands::lookup(command.as_bytes()).ok_or_else(unknown)?;
+            if container.subcommand(subcommand.as_bytes()).is_none() {
+                return Err(unknown());
+            }
+            self.set_subcommand(name, allow);
+        } else {
+            let command = commands::lookup(name.as_bytes()).ok_or_else(unknown)?.name;
+            self.set_command(command, allow);
+        }
+        self.command_rules.push(rule.to_owned());
+        Ok(())
+    }
+
+    fn set_subcommand(&mut self, name: &str, allow: bool) {
+        if allow {
+            self.denied_subcommands.remove(name);
+            self.allowed_subcommands.insert(name.to_owned());
+        } else {
+            self.allowed_subcommands.remove(name);
+            self.denied_subcommands.insert(name.to_owned());
+        }
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&sha256_hex(password.as_bytes()))
+    }
+
+    /// Whether clients using this user have to authenticate first
+    pub fn requires_auth(&self) -> bool {
+        !self.enabled || !self.nopass
+    }
+
+    fn can_run(&self, full_name: &str) -> bool {
+        if self.allowed_subcommands.contains(full_name) {
+            return true;
+        }
+        if self.denied_subcommands.contains(full_name) {
+            return false;
+        }
+        let command = full_name.split('|').next().unwrap_or_default();
+        self.allowed_commands.contains(command)
+    }
+
+    fn can_access_key(&self, key: &[u8], access: KeyAccess) -> bool {
+        self.keys.iter().any(|pattern| {
+            let permitted = match access {
+                KeyAccess::Read => pattern.read,
+                KeyAccess::Write => pattern.write,
+                KeyAccess::ReadWrite => pattern.read && pattern.write,
+            };
+            permitted && glob_match(pattern.pattern.as_bytes(), key, false)
+        })
+    }
+
+    fn can_access_channel(&self, channel: &[u8], literal: bool) -> bool {
+        self.channels.iter().any(|pattern| {
+            // Same as `allchannels`, which grants any pattern too
+            if pattern == "*" {
+                true
+            } else if literal {
+                pattern.as_bytes() == channel
+            } else {
+                glob_match(pattern.as_bytes(), channel, false)
+            }
+        })
+    }
+
+    /// Checks the user may run the command with the given arguments
+    pub fn check(&self, command: &CommandSpec, args: &VecDeque<RespType>) -> Result<(), Denial> {
+        // Every client must be able to authenticate
+        if command.has_flag("no-auth") {
+            return Ok(());
+        }
+        let full_name = full_command_name(command, args);
+        if !self.can_run(&full_name) {
+            return Err(Denial::Command(full_name));
+        }
+        if let Some((key, _)) = command.keys(args).into_iter().find(|(key, access)| !self.can_access_key(key, *access)) {
+            return Err(Denial::Key(key.into()));
+        }
+        let (channels, literal) = command_channels(command.name.as_bytes(), args);
+        if let Some(channel) = channels.into_iter().find(|channel| !self.can_access_channel(channel, literal)) {
+            return Err(Denial::Channel(channel.into()));
+        }
+        Ok(())
+    }
+
+    fn describe_commands(&self) -> String {
+        let mut rules = self.command_rules.clone();
+        if !matches!(rules.first().map(String::as_str), Some("+@all" | "-@all")) {
+            rules.insert(0, String::from("-@all"));
+        }
+        rules.join(" ")
+    }
+
+    fn describe_keys(&self) -> String {
+        self.keys.iter().map(KeyPattern::describe).collect::<Vec<_>>().join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        self.channels.iter().map(|channel| format!("&{channel}")).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Rules recreating the user, as listed by `ACL LIST` and stored in the ACL file
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name), String::from(if self.enabled { "on" } else { "off" })];
+        if self.nopass {
+            parts.push(String::from("nopass"));
+        }
+        parts.extend(self.passwords.iter().map(|hash| format!("#{hash}")));
+        if !self.keys.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        if self.channels.is_empty() {
+            parts.push(String::from("resetchannels"));
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    /// Reply of `ACL GETUSER`
+    pub fn to_resp(&self) -> RespType {
+        let mut flags = VecDeque::from([RespType::bulk_string_from_bytes(if self.enabled { b"on" } else { b"off" })]);
+        if self.nopass {
+            flags.push_back(RespType::bulk_string_from_bytes(b"nopass"));
+        }
+        RespType::Map(vec![
+            (RespType::bulk_string_from_bytes(b"flags"), RespType::Array(flags)),
+            (
+                RespType::bulk_string_from_bytes(b"passwords"),
+                RespType::Array(self.passwords.iter().map(|hash| RespType::bulk_string_from_bytes(hash.as_bytes())).collect()),
+            ),
+            (RespType::bulk_string_from_bytes(b"commands"), RespType::bulk_string_from_string(self.describe_commands())),
+            (RespType::bulk_string_from_bytes(b"keys"), RespType::bulk_string_from_string(self.describe_keys())),
+            (RespType::bulk_string_from_bytes(b"channels"), RespType::bulk_string_from_string(self.describe_channels())),
+            (RespType::bulk_string_from_bytes(b"selectors"), RespType::Array(VecDeque::new())),
+        ])
+    }
+}
+
+#[derive(Debug)]
+struct LogEntry {
+    count: u64,
+    reason: &'static str,
+    context: &'static str,
+    object: String,
+    username: String,
+    client_info: String,
+    entry_id: u64,
+    created: SystemTime,
+    updated: Instant,
+    updated_at: SystemTime,
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Users and the log of denied commands
+#[derive(Debug)]
+pub(crate) struct Acl {
+    users: HashMap<String, User>,
+    /// Newest entries first
+    log: VecDeque<LogEntry>,
+    next_entry_id: u64,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            users: HashMap::from([(String::from("default"), User::new_default())]),
+            log: VecDeque::new(),
+            next_entry_id: 0,
+        }
+    }
+}
+
+impl Acl {
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|user| user.enabled && user.check_password(password))
+    }
+
+    /// Creates or modifies the user, no rule is applied unless all of them are valid
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut user = self.users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply_rule(rule)
+                .map_err(|err| format!("Error in ACL SETUSER modifier '{rule}': {err}"))?;
+        }
+        self.users.insert(name.to_owned(), user);
+        Ok(())
+    }
+
+    pub fn del_user(&mut self, name: &str) -> Result<bool, String> {
+        if name == "default" {
+            return Err(String::from("The 'default' user cannot be removed"));
+        }
+        Ok(self.users.remove(name).is_some())
+    }
+
+    /// User names in alphabetical order
+    pub fn usernames(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.users.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.usernames().into_iter().map(|name| self.users[name].describe()).collect()
+    }
+
+    /// Records the denial to `ACL LOG`, grouping it with a recent one of the same kind
+    pub fn log_denial(&mut self, denial: &Denial, username: &str, in_transaction: bool, client_info: String) {
+        let (reason, object) = denial.reason_and_object();
+        let context = if in_transaction { "multi" } else { "toplevel" };
+        let now = Instant::now();
+        let similar = self.log.iter().position(|entry| {
+            entry.reason == reason
+                && entry.context == context
+                && entry.object == object
+                && entry.username == username
+                && now.duration_since(entry.updated) < ACL_LOG_GROUPING_MAX_TIME_DELTA
+        });
+        let entry = match similar.and_then(|idx| self.log.remove(idx)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.client_info = client_info;
+                entry.updated = now;
+                entry.updated_at = SystemTime::now();
+                entry
+            }
+            None => {
+                self.next_entry_id += 1;
+                LogEntry {
+                    count: 1,
+                    reason,
+                    context,
+                    object,
+                    username: username.to_owned(),
+                    client_info,
+                    entry_id: self.next_entry_id - 1,
+                    created: SystemTime::now(),
+                    updated: now,
+                    updated_at: SystemTime::now(),
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(ACL_LOG_MAX_LEN);
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+
+    /// Reply of `ACL LOG`, newest entries first
+    pub fn log_entries(&self, count: usize) -> RespType {
+        let str = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+        let entries = self.log.iter().take(count).map(|entry| {
+            let age = entry.updated.elapsed().as_secs_f64();
+            RespType::Map(vec![
+                (str("count"), RespType::Integer(entry.count as i64)),
+                (str("reason"), str(entry.reason)),
+                (str("context"), str(entry.context)),
+                (str("object"), str(&entry.object)),
+                (str("username"), str(&entry.username)),
+                (str("age-seconds"), RespType::bulk_string_from_string(format!("{age:.3}"))),
+                (str("client-info"), str(&entry.client_info)),
+                (str("entry-id"), RespType::Integer(entry.entry_id as i64)),
+                (str("timestamp-created"), RespType::Integer(unix_millis(entry.created))),
+                (str("timestamp-last-updated"), RespType::Integer(unix_millis(entry.updated_at))),
+            ])
+        });
+        RespType::Array(entries.collect())
+    }
+
+    /// Replaces all users by the ones defined in the ACL file, keeping the current ones on error
+    pub fn load(&mut self, contents: &str, path: &str) -> Result<(), String> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |err: &str| format!("{path}:{}: {err}", number + 1);
+            let mut words = line.split_whitespace();
+            if words.next() != Some("user") {
+                return Err(error("should start with user keyword"));
+            }
+            let name = words.next().ok_or_else(|| error("user name is missing"))?;
+            if users.contains_key(name) {
+                return Err(error(&format!("duplicate user '{name}' found")));
+            }
+            let mut user = User::new(name);
+            for rule in words {
+                user.apply_rule(rule).map_err(|err| error(&format!("{err}. Error in ACL rule '{rule}'")))?;
+            }
+            users.insert(name.to_owned(), user);
+        }
+        users.entry(String::from("default")).or_insert_with(User::new_default);
+        self.users = users;
+        Ok(())
+    }
+
+    /// Contents of the ACL file with all current users
+    pub fn save(&self) -> String {
+        self.list().into_iter().map(|line| line + "\n").collect()
+    }
+
+    /// Categories, or the commands in the category, listed by `ACL CAT`
+    pub fn categories(category: Option<&str>) -> Option<Vec<&'static str>> {
+        match category {
+            None => Some(CATEGORIES.to_vec()),
+            Some(category) => {
+                let category = CATEGORIES.iter().find(|c| c.eq_ignore_ascii_case(category))?;
+                Some(
+                    commands::all_commands()
+                        .filter(|spec| !spec.is_container() && spec.categories.contains(category))
+                        .map(|spec| spec.name)
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply_rule(rule).unwrap_or_else(|err| panic!("Rule {rule} failed: {err}"));
+        }
+        user
+    }
+
+    fn check(user: &User, command: &[&str]) -> Result<(), Denial> {
+        let spec = commands::lookup(command[0].as_bytes()).expect("Command exists");
+        let args = command[1..].iter().map(|arg| RespType::bulk_string_from_bytes(arg.as_bytes())).collect();
+        user.check(spec, &args)
+    }
+
+    #[test]
+    fn passwords() {
+        let mut alice = user(&["on", ">secret"]);
+        assert!(alice.enabled && alice.requires_auth());
+        assert!(alice.check_password("secret"));
+        assert!(!alice.check_password("other"));
+
+        alice.apply_rule(&format!("#{}", sha256_hex(b"hashed"))).unwrap();
+        assert!(alice.check_password("hashed") && alice.check_password("secret"));
+        assert!(alice.apply_rule("#ABC").is_err());
+        assert!(alice.apply_rule(&format!("#{}", sha256_hex(b"x").to_uppercase())).is_err());
+
+        alice.apply_rule("<secret").unwrap();
+        assert!(!alice.check_password("secret"));
+        assert!(alice.apply_rule("<secret").is_err());
+
+        alice.apply_rule("nopass").unwrap();
+        assert!(alice.check_password("anything") && !alice.requires_auth());
+        alice.apply_rule("resetpass").unwrap();
+        assert!(!alice.check_password("anything") && !alice.check_password("hashed"));
+
+        alice.apply_rule("off").unwrap();
+        assert!(!alice.enabled && alice.requires_auth());
+    }
+
+    #[test]
+    fn key_patterns() {
+        let mut alice = user(&["~cache:*", "%R~config:*"]);
+        assert!(alice.can_access_key(b"cache:1", KeyAccess::ReadWrite));
+        assert!(alice.can_access_key(b"config:1", KeyAccess::Read));
+        assert!(!alice.can_access_key(b"config:1", KeyAccess::Write));
+        assert!(!alice.can_access_key(b"other", KeyAccess::Read));
+        assert_eq!(alice.describe_keys(), "~cache:* %R~config:*");
+        for invalid in ["%X~key", "%~key", "%Rkey"] {
+            assert!(alice.apply_rule(invalid).is_err(), "{invalid}");
+        }
+
+        alice.apply_rule("allkeys").unwrap();
+        assert!(alice.can_access_key(b"other", KeyAccess::Write));
+        alice.apply_rule("resetkeys").unwrap();
+        assert!(!alice.can_access_key(b"cache:1", KeyAccess::Read));
+    }
+
+    #[test]
+    fn command_rules() {
+        let mut alice = user(&["+@read", "-get", "+client|list"]);
+        assert!(alice.can_run("exists") && !alice.can_run("get") && !alice.can_run("set"));
+        assert!(alice.can_run("client|list") && !alice.can_run("client|kill"));
+        assert_eq!(alice.describe_commands(), "-@all +@read -get +client|list");
+        for invalid in ["+@nosuchcategory", "+nosuchcommand", "+client|nosuchsubcommand", "=get"] {
+            assert!(alice.apply_rule(invalid).is_err(), "{invalid}");
+        }
+
+        // `-client` overrides the earlier subcommand rule
+        alice.apply_rule("-client").unwrap();
+        assert!(!alice.can_run("client|list"));
+        alice.apply_rule("+@all").unwrap();
+        assert!(alice.can_run("set") && alice.can_run("client|kill"));
+        assert_eq!(alice.describe_commands(), "+@all");
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let mut alice = user(&["on", ">secret", "~*", "&*", "+@all"]);
+        alice.apply_rule("reset").unwrap();
+        assert!(!alice.enabled && !alice.check_password("secret"));
+        assert!(!alice.can_run("get") && !alice.can_access_key(b"key", KeyAccess::Read));
+        assert_eq!(alice.describe(), "user alice off resetchannels -@all");
+    }
+
+    #[test]
+    fn check_commands_keys_and_channels() {
+        let alice = user(&["on", "nopass", "~app:*", "&news", "+get", "+set", "+publish", "+subscribe", "+psubscribe"]);
+        assert!(check(&alice, &["GET", "app:1"]).is_ok());
+        assert!(check(&alice, &["set", "app:1", "value"]).is_ok());
+        assert!(matches!(check(&alice, &["GET", "other"]), Err(Denial::Key(key)) if &*key == b"other"));
+        assert!(matches!(check(&alice, &["DEL", "app:1"]), Err(Denial::Command(name)) if name == "del"));
+        assert!(check(&alice, &["PUBLISH", "news", "hello"]).is_ok());
+        assert!(matches!(check(&alice, &["PUBLISH", "sports", "hello"]), Err(Denial::Channel(channel)) if &*channel == b"sports"));
+        assert!(matches!(check(&alice, &["SUBSCRIBE", "news", "sports"]), Err(Denial::Channel(channel)) if &*channel == b"sports"));
+        // Patterns must match literally, `news*` could receive messages of other channels
+        assert!(matches!(check(&alice, &["PSUBSCRIBE", "news*"]), Err(Denial::Channel(_))));
+        let all_channels = user(&["on", "nopass", "allchannels", "+psubscribe"]);
+        assert!(check(&all_channels, &["PSUBSCRIBE", "news*"]).is_ok());
+
+        // Anybody may authenticate
+        let nobody = user(&[]);
+        assert!(check(&nobody, &["AUTH", "alice", "secret"]).is_ok());
+        assert!(matches!(check(&nobody, &["GET", "app:1"]), Err(Denial::Command(_))));
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::CommandError;
+use crate::net::PeerAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientKind {
+    Normal,
+    /// Connection from our master, sending us its write commands
+    Master,
+    /// Connection from our replica, receiving our write commands
+    Replica,
+}
+
+/// Type of client used by `CLIENT LIST TYPE` and `CLIENT KILL TYPE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientType {
+    Normal,
+    Master,
+    Replica,
+    /// Client subscribed to some channel
+    PubSub,
+}
+
+impl ClientType {
+    pub(crate) fn parse(name: &str) -> Result<Self, CommandError> {
+        match name.to_ascii_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "master" => Ok(Self::Master),
+            "replica" | "slave" => Ok(Self::Replica),
+            "pubsub" => Ok(Self::PubSub),
+            _ => Err(CommandError::err(format!("Unknown client type '{name}'"))),
+        }
+    }
+}
+
+/// Snapshot of a connection's state, kept in the server's client registry
+#[derive(Debug, Clone)]
+pub(crate) struct ClientInfo {
+    pub id: u64,
+    pub addr: PeerAddr,
+    pub laddr: PeerAddr,
+    /// Set by `CLIENT SETNAME`
+    pub name: String,
+    pub created: Instant,
+    /// When the client last sent a command
+    pub last_interaction: Instant,
+    pub kind: ClientKind,
+    pub db: usize,
+    pub subscriptions: usize,
+    pub pattern_subscriptions: usize,
+    pub shard_subscriptions: usize,
+    /// Number of commands queued after `MULTI`, -1 outside of a transaction
+    pub multi: i64,
+    pub watch: usize,
+    /// Request data received but not parsed yet
+    pub query_buffer: usize,
+    /// Replies encoded but not written to the socket yet
+    pub output_buffer: usize,
+    /// Output buffer plus pushes and replicated commands queued for the client
+    pub output_memory: usize,
+    /// Full name of the last command, e.g. `client|list`
+    pub last_command: String,
+    pub user: String,
+    /// Redirect of `CLIENT TRACKING`, 0 without redirect and -1 when tracking is off
+    pub redirect: i64,
+    pub resp: u8,
+    pub no_evict: bool,
+    pub closing: bool,
+    /// Set by `CLIENT SETINFO LIB-NAME`
+    pub lib_name: String,
+    /// Set by `CLIENT SETINFO LIB-VER`
+    pub lib_ver: String,
+}
+
+impl ClientInfo {
+    pub(crate) fn client_type(&self) -> ClientType {
+        match self.kind {
+            ClientKind::Master => ClientType::Master,
+            ClientKind::Replica => ClientType::Replica,
+            ClientKind::Normal if self.subscriptions + self.pattern_subscriptions + self.shard_subscriptions > 0 => {
+                ClientType::PubSub
+            }
+            ClientKind::Normal => ClientType::Normal,
+        }
+    }
+
+    /// Flags shown in `CLIENT LIST`, `N` when no flag is set
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        match self.kind {
+            ClientKind::Master => flags.push('M'),
+            ClientKind::Replica => flags.push('S'),
+            ClientKind::Normal => {}
+        }
+        if self.client_type() == ClientType::PubSub {
+            flags.push('P');
+        }
+        if self.multi >= 0 {
+            flags.push('x');
+        }
+        if self.closing {
+            flags.push('A');
+        }
+        if self.redirect >= 0 {
+            flags.push('t');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+}
+
+/// Line of `CLIENT LIST` and `CLIENT INFO`
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} addr={} laddr={} fd=-1 name={} age={} idle={} flags={} db={} sub={} psub={} ssub={} multi={} watch={} ",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name,
+            self.created.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.flags(),
+            self.db,
+            self.subscriptions,
+            self.pattern_subscriptions,
+            self.shard_subscriptions,
+            self.multi,
+            self.watch,
+        )?;
+        write!(
+            f,
+            "qbuf={} qbuf-free=0 argv-mem=0 multi-mem=0 rbs={} rbp=0 obl={} oll=0 omem={} tot-mem={} events=r cmd={} user={} redir={} resp={} lib-name={} lib-ver={}",
+            self.query_buffer,
+            self.query_buffer,
+            self.output_buffer,
+            self.output_memory,
+            self.query_buffer + self.output_memory,
+            self.last_command,
+            self.user,
+            self.redirect,
+            self.resp,
+            self.lib_name,
+            self.lib_ver,
+        )
+    }
+}
+
+/// Filter of `CLIENT KILL` and `CLIENT LIST`, a client has to match all the given criteria
+#[derive(Debug, Default)]
+pub(crate) struct ClientFilter {
+    pub ids: Option<Vec<u64>>,
+    pub addr: Option<String>,
+    pub laddr: Option<String>,
+    pub user: Option<String>,
+    pub client_type: Option<ClientType>,
+    /// Clients connected for at least this long
+    pub max_age: Option<Duration>,
+    /// Clients which sent no command for at least this long
+    pub idle: Option<Duration>,
+    /// Client excluded from the results, the caller unless `SKIPME no`
+    pub skip: Option<u64>,
+}
+
+impl ClientFilter {
+    pub(crate) fn matches(&self, client: &ClientInfo) -> bool {
+        self.ids.as_ref().is_none_or(|ids| ids.contains(&client.id))
+            && self.addr.as_ref().is_none_or(|addr| *addr == client.addr.to_string())
+            && self.laddr.as_ref().is_none_or(|laddr| *laddr == client.laddr.to_string())
+            && self.user.as_ref().is_none_or(|user| *user == client.user)
+            && self.client_type.is_none_or(|client_type| client_type == client.client_type())
+            && self.max_age.is_none_or(|max_age| client.created.elapsed() >= max_age)
+            && self.idle.is_none_or(|idle| client.last_interaction.elapsed() >= idle)
+            && self.skip != Some(client.id)
+    }
+}
+
+/// Client names, library names and versions are shown in `CLIENT LIST`, so they can't contain spaces
+pub(crate) fn is_valid_name(name: &[u8]) -> bool {
+    name.iter().all(|c| (b'!'..=b'~').contains(c))
+}
@@ -0,0 +1,494 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+
+use crate::glob::glob_match;
+use crate::resp::RespType;
+
+/// Position of keys among the arguments, a simplified Redis key specification
+#[derive(Debug)]
+pub(crate) struct KeySpec {
+    /// Access flags, e.g. `RO`, `RW`, `OW` or `RM` followed by the kind of the operation
+    pub flags: &'static [&'static str],
+    /// Index of the first key, the command name being at index 0
+    pub begin: usize,
+    /// Index of the last key relative to `begin`, negative numbers count from the end of arguments
+    pub last: isize,
+    pub step: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl KeySpec {
+    pub fn access(&self) -> KeyAccess {
+        if self.flags.contains(&"RW") {
+            KeyAccess::ReadWrite
+        } else if self.flags.contains(&"RO") {
+            KeyAccess::Read
+        } else {
+            KeyAccess::Write
+        }
+    }
+
+    fn to_resp(&self) -> RespType {
+        let str = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+        RespType::Map(vec![
+            (str("flags"), RespType::Array(self.flags.iter().map(|flag| str(flag)).collect())),
+            (
+                str("begin_search"),
+                RespType::Map(vec![
+                    (str("type"), str("index")),
+                    (str("spec"), RespType::Map(vec![(str("index"), RespType::Integer(self.begin as i64))])),
+                ]),
+            ),
+            (
+                str("find_keys"),
+                RespType::Map(vec![
+                    (str("type"), str("range")),
+                    (
+                        str("spec"),
+                        RespType::Map(vec![
+                            (str("lastkey"), RespType::Integer(self.last as i64)),
+                            (str("keystep"), RespType::Integer(self.step as i64)),
+                            (str("limit"), RespType::Integer(0)),
+                        ]),
+                    ),
+                ]),
+            ),
+        ])
+    }
+}
+
+/// Code executing the command, subcommands share the handler of their container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Handler {
+    Get,
+    Set,
+    Del,
+    Unlink,
+    Exists,
+    Touch,
+    Type,
+    Rename,
+    RenameNx,
+    Copy,
+    Move,
+    RandomKey,
+    DbSize,
+    Keys,
+    Scan,
+    Select,
+    SwapDb,
+    FlushDb,
+    FlushAll,
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+    Ping,
+    Echo,
+    Hello,
+    Auth,
+    Quit,
+    Reset,
+    Publish,
+    SPublish,
+    Subscribe,
+    PSubscribe,
+    SSubscribe,
+    Unsubscribe,
+    PUnsubscribe,
+    SUnsubscribe,
+    PubSub,
+    Client,
+    Config,
+    Acl,
+    Command,
+    Info,
+    ReplConf,
+    PSync,
+}
+
+/// Static description of a command, see `COMMAND INFO` and `COMMAND DOCS` in Redis
+#[derive(Debug)]
+pub(crate) struct CommandSpec {
+    /// Lowercase name, `container|subcommand` for subcommands
+    pub name: &'static str,
+    pub handler: Handler,
+    /// Number of arguments including the command name, negative number means "at least"
+    pub arity: isize,
+    pub flags: &'static [&'static str],
+    pub categories: &'static [&'static str],
+    pub keys: &'static [KeySpec],
+    pub group: &'static str,
+    pub summary: &'static str,
+    pub since: &'static str,
+    pub subcommands: &'static [CommandSpec],
+    /// Runs while clients are paused, so the pause can be lifted
+    pub pause_exempt: bool,
+}
+
+const NO_KEYS: &[KeySpec] = &[];
+
+const fn key(flags: &'static [&'static str]) -> KeySpec {
+    KeySpec { flags, begin: 1, last: 0, step: 1 }
+}
+
+const fn all_keys(flags: &'static [&'static str]) -> KeySpec {
+    KeySpec { flags, begin: 1, last: -1, step: 1 }
+}
+
+#[allow(clippy::too_many_arguments)]
+const fn command(
+    name: &'static str,
+    handler: Handler,
+    arity: isize,
+    flags: &'static [&'static str],
+    categories: &'static [&'static str],
+    keys: &'static [KeySpec],
+    group: &'static str,
+    summary: &'static str,
+    since: &'static str,
+) -> CommandSpec {
+    CommandSpec { name, handler, arity, flags, categories, keys, group, summary, since, subcommands: &[], pause_exempt: false }
+}
+
+const fn container(
+    name: &'static str,
+    handler: Handler,
+    group: &'static str,
+    summary: &'static str,
+    since: &'static str,
+    subcommands: &'static [CommandSpec],
+) -> CommandSpec {
+    CommandSpec { name, handler, arity: -2, flags: &[], categories: &["slow"], keys: NO_KEYS, group, summary, since, subcommands, pause_exempt: false }
+}
+
+const fn pause_exempt(spec: CommandSpec) -> CommandSpec {
+    CommandSpec { pause_exempt: true, ..spec }
+}
+
+pub(crate) static COMMANDS: &[CommandSpec] = &[
+    command("get", Handler::Get, 2, &["readonly", "fast"], &["read", "string", "fast"], &[key(&["RO", "access"])], "string", "Returns the string value of a key.", "1.0.0"),
+    command("set", Handler::Set, -3, &["write", "denyoom"], &["write", "string", "slow"], &[key(&["OW", "update"])], "string", "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.", "1.0.0"),
+    command("del", Handler::Del, -2, &["write"], &["keyspace", "write", "slow"], &[all_keys(&["RM", "delete"])], "generic", "Deletes one or more keys.", "1.0.0"),
+    command("unlink", Handler::Unlink, -2, &["write", "fast"], &["keyspace", "write", "fast"], &[all_keys(&["RM", "delete"])], "generic", "Asynchronously deletes one or more keys.", "4.0.0"),
+    command("exists", Handler::Exists, -2, &["readonly", "fast"], &["keyspace", "read", "fast"], &[all_keys(&["RO"])], "generic", "Determines whether one or more keys exist.", "1.0.0"),
+    command("touch", Handler::Touch, -2, &["readonly", "fast"], &["keyspace", "read", "fast"], &[all_keys(&["RO"])], "generic", "Returns the number of existing keys out of those specified after updating the time they were last accessed.", "3.2.1"),
+    command("type", Handler::Type, 2, &["readonly", "fast"], &["keyspace", "read", "fast"], &[key(&["RO"])], "generic", "Determines the type of value stored at a key.", "1.0.0"),
+    command("rename", Handler::Rename, 3, &["write"], &["keyspace", "write", "slow"], &[key(&["RW", "access", "delete"]), KeySpec { flags: &["OW", "update"], begin: 2, last: 0, step: 1 }], "generic", "Renames a key and overwrites the destination.", "1.0.0"),
+    command("renamenx", Handler::RenameNx, 3, &["write", "fast"], &["keyspace", "write", "fast"], &[key(&["RW", "access", "delete"]), KeySpec { flags: &["OW", "insert"], begin: 2, last: 0, step: 1 }], "generic", "Renames a key only when the target key name doesn't exist.", "1.0.0"),
+    command("copy", Handler::Copy, -3, &["write", "denyoom"], &["keyspace", "write", "slow"], &[key(&["RO", "access"]), KeySpec { flags: &["OW", "update"], begin: 2, last: 0, step: 1 }], "generic", "Copies the value of a key to a new key.", "6.2.0"),
+    command("move", Handler::Move, 3, &["write", "fast"], &["keyspace", "write", "fast"], &[key(&["RW", "access", "delete"])], "generic", "Moves a key to another database.", "1.0.0"),
+    command("randomkey", Handler::RandomKey, 1, &["readonly"], &["keyspace", "read", "slow"], NO_KEYS, "generic", "Returns a random key name from the database.", "1.0.0"),
+    command("dbsize", Handler::DbSize, 1, &["readonly", "fast"], &["keyspace", "read", "fast"], NO_KEYS, "server", "Returns the number of keys in the database.", "1.0.0"),
+    command("keys", Handler::Keys, 2, &["readonly"], &["keyspace", "read", "slow", "dangerous"], NO_KEYS, "generic", "Returns all key names that match a pattern.", "1.0.0"),
+    command("scan", Handler::Scan, -2, &["readonly"], &["keyspace", "read", "slow"], NO_KEYS, "generic", "Iterates over the key names in the database.", "2.8.0"),
+    command("select", Handler::Select, 2, &["loading", "stale", "fast"], &["fast", "connection"], NO_KEYS, "connection", "Changes the selected database.", "1.0.0"),
+    command("swapdb", Handler::SwapDb, 3, &["write", "fast"], &["keyspace", "write", "fast", "dangerous"], NO_KEYS, "server", "Swaps two Redis databases.", "4.0.0"),
+    command("flushdb", Handler::FlushDb, -1, &["write"], &["keyspace", "write", "slow", "dangerous"], NO_KEYS, "server", "Remove all keys from the current database.", "1.0.0"),
+    command("flushall", Handler::FlushAll, -1, &["write"], &["keyspace", "write", "slow", "dangerous"], NO_KEYS, "server", "Removes all keys from all databases.", "1.0.0"),
+    command("multi", Handler::Multi, 1, &["noscript", "loading", "stale", "fast", "allow-busy"], &["fast", "transaction"], NO_KEYS, "transactions", "Starts a transaction.", "1.2.0"),
+    command("exec", Handler::Exec, 1, &["noscript", "loading", "stale", "skip-slowlog"], &["slow", "transaction"], NO_KEYS, "transactions", "Executes all commands in a transaction.", "1.2.0"),
+    command("discard", Handler::Discard, 1, &["noscript", "loading", "stale", "fast", "allow-busy"], &["fast", "transaction"], NO_KEYS, "transactions", "Discards a transaction.", "2.0.0"),
+    command("watch", Handler::Watch, -2, &["noscript", "loading", "stale", "fast", "allow-busy", "no-multi"], &["fast", "transaction"], &[all_keys(&["RO"])], "transactions", "Monitors changes to keys to determine the execution of a transaction.", "2.2.0"),
+    command("unwatch", Handler::Unwatch, 1, &["noscript", "loading", "stale", "fast", "allow-busy"], &["fast", "transaction"], NO_KEYS, "transactions", "Forgets about watched keys of a transaction.", "2.2.0"),
+    command("ping", Handler::Ping, -1, &["fast"], &["fast", "connection"], NO_KEYS, "connection", "Returns the server's liveliness response.", "1.0.0"),
+    command("echo", Handler::Echo, 2, &["fast"], &["fast", "connection"], NO_KEYS, "connection", "Returns the given string.", "1.0.0"),
+    command("hello", Handler::Hello, -1, &["noscript", "loading", "stale", "fast", "no-auth", "allow-busy"], &["fast", "connection"], NO_KEYS, "connection", "Handshakes with the Redis server.", "6.0.0"),
+    command("auth", Handler::Auth, -2, &["noscript", "loading", "stale", "fast", "no-auth", "allow-busy"], &["fast", "connection"], NO_KEYS, "connection", "Authenticates the connection.", "1.0.0"),
+    command("quit", Handler::Quit, -1, &["noscript", "loading", "stale", "fast", "no-auth", "allow-busy"], &["fast", "connection"], NO_KEYS, "connection", "Closes the connection.", "1.0.0"),
+    command("reset", Handler::Reset, 1, &["noscript", "loading", "stale", "fast", "no-auth", "allow-busy"], &["fast", "connection"], NO_KEYS, "connection", "Resets the connection.", "6.2.0"),
+    command("publish", Handler::Publish, 3, &["pubsub", "loading", "stale", "fast", "may-replicate"], &["pubsub", "fast"], NO_KEYS, "pubsub", "Posts a message to a channel.", "2.0.0"),
+    command("spublish", Handler::SPublish, 3, &["pubsub", "loading", "stale", "fast", "may-replicate"], &["pubsub", "fast"], NO_KEYS, "pubsub", "Post a message to a shard channel", "7.0.0"),
+    command("subscribe", Handler::Subscribe, -2, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Listens for messages published to channels.", "2.0.0"),
+    command("psubscribe", Handler::PSubscribe, -2, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Listens for messages published to channels that match one or more patterns.", "2.0.0"),
+    command("ssubscribe", Handler::SSubscribe, -2, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Listens for messages published to shard channels.", "7.0.0"),
+    command("unsubscribe", Handler::Unsubscribe, -1, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Stops listening to messages posted to channels.", "2.0.0"),
+    command("punsubscribe", Handler::PUnsubscribe, -1, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Stops listening to messages published to channels that match one or more patterns.", "2.0.0"),
+    command("sunsubscribe", Handler::SUnsubscribe, -1, &["pubsub", "noscript", "loading", "stale", "no-multi"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Stops listening to messages posted to shard channels.", "7.0.0"),
+    container("pubsub", Handler::PubSub, "pubsub", "A container for Pub/Sub commands.", "2.8.0", &[
+        command("pubsub|channels", Handler::PubSub, -2, &["pubsub", "loading", "stale"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Returns the active channels.", "2.8.0"),
+        command("pubsub|numpat", Handler::PubSub, 2, &["pubsub", "loading", "stale"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Returns a count of unique pattern subscriptions.", "2.8.0"),
+        command("pubsub|numsub", Handler::PubSub, -2, &["pubsub", "loading", "stale"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Returns a count of subscribers to channels.", "2.8.0"),
+        command("pubsub|shardchannels", Handler::PubSub, -2, &["pubsub", "loading", "stale"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Returns the active shard channels.", "7.0.0"),
+        command("pubsub|shardnumsub", Handler::PubSub, -2, &["pubsub", "loading", "stale"], &["pubsub", "slow"], NO_KEYS, "pubsub", "Returns the count of subscribers of shard channels.", "7.0.0"),
+    ]),
+    container("client", Handler::Client, "connection", "A container for client connection commands.", "2.4.0", &[
+        command("client|caching", Handler::Client, 3, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Instructs the server whether to track the keys in the next request.", "6.0.0"),
+        command("client|getname", Handler::Client, 2, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Returns the name of the connection.", "2.6.9"),
+        command("client|getredir", Handler::Client, 2, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Returns the client ID to which the connection's tracking notifications are redirected.", "6.0.0"),
+        command("client|id", Handler::Client, 2, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Returns the unique client ID of the connection.", "5.0.0"),
+        command("client|info", Handler::Client, 2, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Returns information about the connection.", "6.2.0"),
+        command("client|kill", Handler::Client, -3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous", "connection"], NO_KEYS, "connection", "Terminates open connections.", "2.4.0"),
+        command("client|list", Handler::Client, -2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous", "connection"], NO_KEYS, "connection", "Lists open connections.", "2.4.0"),
+        command("client|no-evict", Handler::Client, 3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous", "connection"], NO_KEYS, "connection", "Sets the client eviction mode of the connection.", "7.0.0"),
+        command("client|pause", Handler::Client, -3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous", "connection"], NO_KEYS, "connection", "Suspends commands processing.", "3.0.0"),
+        command("client|setinfo", Handler::Client, 4, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Sets information specific to the client or connection.", "7.2.0"),
+        command("client|setname", Handler::Client, 3, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Sets the connection name.", "2.6.9"),
+        command("client|tracking", Handler::Client, -3, &["noscript", "loading", "stale"], &["slow", "connection"], NO_KEYS, "connection", "Controls server-assisted client-side caching for the connection.", "6.0.0"),
+        pause_exempt(command("client|unpause", Handler::Client, 2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous", "connection"], NO_KEYS, "connection", "Resumes processing commands from paused clients.", "6.2.0")),
+    ]),
+    container("config", Handler::Config, "server", "A container for server configuration commands.", "2.0.0", &[
+        command("config|get", Handler::Config, -3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Returns the effective values of configuration parameters.", "2.0.0"),
+        command("config|set", Handler::Config, -4, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Sets configuration parameters in-flight.", "2.0.0"),
+    ]),
+    container("acl", Handler::Acl, "server", "A container for Access List Control commands.", "6.0.0", &[
+        command("acl|cat", Handler::Acl, -2, &["noscript", "loading", "stale"], &["slow"], NO_KEYS, "server", "Lists the ACL categories, or the commands inside a category.", "6.0.0"),
+        command("acl|deluser", Handler::Acl, -3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Deletes ACL users, and terminates their connections.", "6.0.0"),
+        command("acl|dryrun", Handler::Acl, -4, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Simulates the execution of a command by a user, without executing the command.", "7.0.0"),
+        command("acl|getuser", Handler::Acl, 3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Lists the ACL rules of a user.", "6.0.0"),
+        command("acl|list", Handler::Acl, 2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Dumps the effective rules in ACL file format.", "6.0.0"),
+        command("acl|load", Handler::Acl, 2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Reloads the rules from the configured ACL file.", "6.0.0"),
+        command("acl|log", Handler::Acl, -2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Lists recent security events generated due to ACL rules.", "6.0.0"),
+        command("acl|save", Handler::Acl, 2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Saves the effective ACL rules in the configured ACL file.", "6.0.0"),
+        command("acl|setuser", Handler::Acl, -3, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Creates and modifies an ACL user and its rules.", "6.0.0"),
+        command("acl|users", Handler::Acl, 2, &["admin", "noscript", "loading", "stale"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "Lists all ACL users.", "6.0.0"),
+        command("acl|whoami", Handler::Acl, 2, &["noscript", "loading", "stale"], &["slow"], NO_KEYS, "server", "Returns the authenticated username of the current connection.", "6.0.0"),
+    ]),
+    CommandSpec {
+        name: "command",
+        handler: Handler::Command,
+        arity: -1,
+        flags: &["loading", "stale"],
+        categories: &["slow", "connection"],
+        keys: NO_KEYS,
+        group: "server",
+        summary: "Returns detailed information about all commands.",
+        since: "2.8.13",
+        subcommands: &[
+            command("command|count", Handler::Command, 2, &["loading", "stale"], &["slow", "connection"], NO_KEYS, "server", "Returns a count of commands.", "2.8.13"),
+            command("command|docs", Handler::Command, -2, &["loading", "stale"], &["slow", "connection"], NO_KEYS, "server", "Returns documentary information about one, multiple or all commands.", "7.0.0"),
+            command("command|getkeys", Handler::Command, -3, &["loading", "stale"], &["slow", "connection"], NO_KEYS, "server", "Extracts the key names from an arbitrary command.", "2.8.13"),
+            command("command|info", Handler::Command, -2, &["loading", "stale"], &["slow", "connection"], NO_KEYS, "server", "Returns information about one, multiple or all commands.", "2.8.13"),
+            command("command|list", Handler::Command, -2, &["loading", "stale"], &["slow", "connection"], NO_KEYS, "server", "Returns a list of command names.", "7.0.0"),
+        ],
+        pause_exempt: false,
+    },
+    command("info", Handler::Info, -1, &["loading", "stale"], &["slow", "dangerous"], NO_KEYS, "server", "Returns information and statistics about the server.", "1.0.0"),
+    command("replconf", Handler::ReplConf, -1, &["admin", "noscript", "loading", "stale", "allow-busy"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "An internal command for configuring the replication stream.", "3.0.0"),
+    command("psync", Handler::PSync, -3, &["admin", "noscript", "no-multi"], &["admin", "slow", "dangerous"], NO_KEYS, "server", "An internal command used in replication.", "2.8.0"),
+];
+
+/// Looks up command by name, ignoring case
+pub(crate) fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    static BY_NAME: OnceLock<HashMap<&[u8], &CommandSpec>> = OnceLock::new();
+    // Lowercased on the stack, no command has a longer name
+    let mut lowercase = [0; 32];
+    let lowercase = lowercase.get_mut(..name.len())?;
+    lowercase.copy_from_slice(name);
+    lowercase.make_ascii_lowercase();
+    BY_NAME
+        .get_or_init(|| COMMANDS.iter().map(|spec| (spec.name.as_bytes(), spec)).collect())
+        .get(&*lowercase)
+        .copied()
+}
+
+/// Looks up command or subcommand by name such as `config|get`, ignoring case
+pub(crate) fn lookup_full(name: &[u8]) -> Option<&'static CommandSpec> {
+    match name.iter().position(|&b| b == b'|') {
+        Some(separator) => lookup(&name[..separator])?.subcommand(&name[separator + 1..]),
+        None => lookup(name),
+    }
+}
+
+/// Commands and their subcommands
+pub(crate) fn all_commands() -> impl Iterator<Item = &'static CommandSpec> {
+    COMMANDS.iter().flat_map(|spec| std::iter::once(spec).chain(spec.subcommands))
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    pub fn is_container(&self) -> bool {
+        !self.subcommands.is_empty()
+    }
+
+    /// Finds subcommand of the container by its name given as the first argument
+    pub fn subcommand(&self, name: &[u8]) -> Option<&'static CommandSpec> {
+        self.subcommands
+            .iter()
+            .find(|sub| sub.name.split_once('|').is_some_and(|(_, sub)| sub.as_bytes().eq_ignore_ascii_case(name)))
+    }
+
+    /// Subcommand called with the arguments, the command itself unless it's a container
+    pub fn resolve(&'static self, args: &VecDeque<RespType>) -> &'static CommandSpec {
+        args.front()
+            .filter(|_| self.is_container())
+            .and_then(|arg| arg.as_str_bytes().ok())
+            .and_then(|name| self.subcommand(name))
+            .unwrap_or(self)
+    }
+
+    /// Checks the number of arguments (without the command name)
+    pub fn arity_matches(&self, args: usize) -> bool {
+        let argc = args as isize + 1;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    /// Keys in the arguments (without the command name) with the kind of the access
+    pub fn keys<'a>(&self, args: &'a VecDeque<RespType>) -> Vec<(&'a [u8], KeyAccess)> {
+        let mut keys = Vec::new();
+        for spec in self.keys {
+            let first = spec.begin - 1;
+            let last = if spec.last < 0 {
+                args.len() as isize + spec.last
+            } else {
+                first as isize + spec.last
+            };
+            let last = usize::try_from(last).unwrap_or(0).min(args.len().saturating_sub(1));
+            for arg in args.iter().take(last + 1).skip(first).step_by(spec.step) {
+                if let Ok(key) = arg.as_str_bytes() {
+                    keys.push((key, spec.access()));
+                }
+            }
+        }
+        keys
+    }
+
+    /// Legacy first key, last key and step reported by `COMMAND INFO`
+    fn key_range(&self) -> (i64, i64, i64) {
+        let Some(first) = self.keys.first() else {
+            return (0, 0, 0);
+        };
+        let last = self.keys.iter().try_fold(0, |last, spec| {
+            (spec.last >= 0).then(|| last.max(spec.begin as i64 + spec.last as i64))
+        });
+        (first.begin as i64, last.unwrap_or(-1), first.step as i64)
+    }
+
+    /// Reply of `COMMAND INFO`
+    pub fn info(&self) -> RespType {
+        let str = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+        let (first, last, step) = self.key_range();
+        RespType::Array(VecDeque::from([
+            str(self.name),
+            RespType::Integer(self.arity as i64),
+            RespType::Array(self.flags.iter().map(|flag| RespType::SimpleString(flag.to_string())).collect()),
+            RespType::Integer(first),
+            RespType::Integer(last),
+            RespType::Integer(step),
+            RespType::Array(self.categories.iter().map(|category| RespType::SimpleString(format!("@{category}"))).collect()),
+            RespType::Array(VecDeque::new()),
+            RespType::Array(self.keys.iter().map(KeySpec::to_resp).collect()),
+            RespType::Array(self.subcommands.iter().map(CommandSpec::info).collect()),
+        ]))
+    }
+
+    /// Reply of `COMMAND DOCS` for the command (without its name)
+    pub fn docs(&self) -> RespType {
+        let str = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+        let mut docs = vec![
+            (str("summary"), str(self.summary)),
+            (str("since"), str(self.since)),
+            (str("group"), str(self.group)),
+        ];
+        if self.is_container() {
+            let subcommands = self.subcommands.iter().map(|sub| (str(sub.name), sub.docs())).collect();
+            docs.push((str("subcommands"), RespType::Map(subcommands)));
+        }
+        RespType::Map(docs)
+    }
+}
+
+/// Filter of `COMMAND LIST FILTERBY`
+pub(crate) enum CommandFilter {
+    AclCategory(String),
+    Pattern(Box<[u8]>),
+    Module,
+}
+
+impl CommandFilter {
+    pub fn matches(&self, spec: &CommandSpec) -> bool {
+        match self {
+            Self::AclCategory(category) => spec.categories.iter().any(|c| c.eq_ignore_ascii_case(category)),
+            Self::Pattern(pattern) => glob_match(pattern, spec.name.as_bytes(), true),
+            // Modules aren't supported
+            Self::Module => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> VecDeque<RespType> {
+        args.iter().map(|arg| RespType::bulk_string_from_bytes(arg.as_bytes())).collect()
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup(b"GeT").map(|spec| spec.name), Some("get"));
+        assert_eq!(lookup_full(b"CONFIG|Get").map(|spec| spec.name), Some("config|get"));
+        assert!(lookup(b"config|get").is_none());
+        assert!(lookup_full(b"config|nosuchsubcommand").is_none());
+        assert!(lookup(b"").is_none());
+        assert!(lookup(&[b'a'; 100]).is_none());
+    }
+
+    #[test]
+    fn names_are_unique_lowercase_and_fit_lookup() {
+        let mut names = HashSet::new();
+        for spec in all_commands() {
+            assert!(names.insert(spec.name), "{} is defined twice", spec.name);
+            assert_eq!(spec.name, spec.name.to_ascii_lowercase());
+            assert!(lookup_full(spec.name.as_bytes()).is_some_and(|found| std::ptr::eq(found, spec)), "{}", spec.name);
+        }
+    }
+
+    #[test]
+    fn arity() {
+        let get = lookup(b"get").unwrap();
+        assert!(get.arity_matches(1) && !get.arity_matches(0) && !get.arity_matches(2));
+        let set = lookup(b"set").unwrap();
+        assert!(!set.arity_matches(1) && set.arity_matches(2) && set.arity_matches(5));
+    }
+
+    #[test]
+    fn keys_follow_key_specs() {
+        let keys = |name: &str, arguments: &[&str]| {
+            let arguments = args(arguments);
+            lookup(name.as_bytes()).unwrap().keys(&arguments).into_iter().map(|(key, access)| (key.to_vec(), access)).collect::<Vec<_>>()
+        };
+        assert_eq!(keys("get", &["a"]), [(b"a".to_vec(), KeyAccess::Read)]);
+        assert_eq!(keys("set", &["a", "value", "EX", "10"]), [(b"a".to_vec(), KeyAccess::Write)]);
+        assert_eq!(keys("del", &["a", "b"]), [(b"a".to_vec(), KeyAccess::Write), (b"b".to_vec(), KeyAccess::Write)]);
+        assert_eq!(keys("rename", &["a", "b"]), [(b"a".to_vec(), KeyAccess::ReadWrite), (b"b".to_vec(), KeyAccess::Write)]);
+        assert!(keys("ping", &[]).is_empty());
+
+        assert_eq!(lookup(b"get").unwrap().key_range(), (1, 1, 1));
+        assert_eq!(lookup(b"del").unwrap().key_range(), (1, -1, 1));
+        assert_eq!(lookup(b"rename").unwrap().key_range(), (1, 2, 1));
+    }
+
+    #[test]
+    fn subcommands_resolve_from_arguments() {
+        let client = lookup(b"client").unwrap();
+        assert_eq!(client.resolve(&args(&["UNPAUSE"])).name, "client|unpause");
+        assert!(client.resolve(&args(&["UNPAUSE"])).pause_exempt);
+        assert_eq!(client.resolve(&args(&["nosuchsubcommand"])).name, "client");
+        assert_eq!(lookup(b"get").unwrap().resolve(&args(&["unpause"])).name, "get");
+    }
+
+    #[test]
+    fn list_filters() {
+        let names = |filter: CommandFilter| COMMANDS.iter().filter(|spec| filter.matches(spec)).map(|spec| spec.name).collect::<Vec<_>>();
+        assert_eq!(names(CommandFilter::Pattern(Box::from(&b"FLUSH*"[..]))), ["flushdb", "flushall"]);
+        assert!(names(CommandFilter::AclCategory(String::from("Transaction"))).contains(&"multi"));
+        assert!(names(CommandFilter::Module).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::net::TCP_KEEPALIVE_SUPPORTED;
+use crate::output::OutputBufferLimits;
+use crate::ReplicationMode;
+
+/// Address from `bind`, `*` and `::*` stand for all IPv4 and IPv6 addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindAddr {
+    pub ip: IpAddr,
+    /// Set by `-` prefix, the address is skipped if it can't be bound
+    pub optional: bool,
+}
+
+impl FromStr for BindAddr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (optional, addr) = match s.strip_prefix('-') {
+            Some(addr) => (true, addr),
+            None => (false, s),
+        };
+        let ip = match addr {
+            "*" => Ipv4Addr::UNSPECIFIED.into(),
+            "::*" => Ipv6Addr::UNSPECIFIED.into(),
+            addr => addr.parse()?,
+        };
+        Ok(Self { ip, optional })
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.optional {
+            f.write_str("-")?;
+        }
+        match self.ip {
+            IpAddr::V4(ip) if ip.is_unspecified() => f.write_str("*"),
+            IpAddr::V6(ip) if ip.is_unspecified() => f.write_str("::*"),
+            ip => write!(f, "{ip}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    /// Addresses to listen on, the TCP port is the same for all of them
+    pub bind: Vec<BindAddr>,
+    /// Refuses clients connecting from other than loopback addresses while the default user has no password
+    pub protected_mode: bool,
+    /// TCP port to listen on, 0 disables TCP
+    pub port: u16,
+    /// Path of Unix socket to listen on besides TCP
+    pub unixsocket: Option<PathBuf>,
+    /// Permissions of the Unix socket file, `None` keeps the default ones
+    pub unixsocketperm: Option<u32>,
+    pub replication: ReplicationMode,
+    /// Number of logical databases, selectable by `SELECT`
+    pub databases: usize,
+    /// Classes of keyspace events published to pub/sub, see `notify-keyspace-events` in Redis
+    pub notify_keyspace_events: String,
+    /// Password clients have to authenticate with by `AUTH`
+    pub requirepass: Option<String>,
+    /// Password used to authenticate with our master
+    pub masterauth: Option<String>,
+    /// File with ACL users, loaded on startup
+    pub aclfile: Option<PathBuf>,
+    /// Maximum length of bulk string in requests
+    pub proto_max_bulk_len: usize,
+    /// Maximum number of items of array in requests
+    pub proto_max_multibulk_len: usize,
+    /// Maximum depth of nested arrays in requests
+    pub proto_max_nesting: usize,
+    /// Maximum length of inline command or any other line in requests
+    pub proto_max_line_len: usize,
+    /// Limits of output waiting to be written to a client, see `client-output-buffer-limit` in Redis
+    pub client_output_buffer_limit: OutputBufferLimits,
+    /// Maximum number of connected clients, new connections above it are rejected
+    pub maxclients: usize,
+    /// Seconds after which idle clients are closed, 0 disables the timeout
+    pub timeout: u64,
+    /// Interval of TCP keepalive probes sent to clients in seconds, 0 disables them
+    pub tcp_keepalive: u64,
+    /// Maximum number of keys in the invalidation table of client-side caching, 0 means no limit
+    pub tracking_table_max_keys: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![
+                BindAddr {
+                    ip: Ipv4Addr::LOCALHOST.into(),
+                    optional: false,
+                },
+                BindAddr {
+                    ip: Ipv6Addr::LOCALHOST.into(),
+                    optional: true,
+                },
+            ],
+            protected_mode: true,
+            port: 6379,
+            unixsocket: None,
+            unixsocketperm: None,
+            replication: ReplicationMode::Master,
+            databases: 16,
+            notify_keyspace_events: String::new(),
+            requirepass: None,
+            masterauth: None,
+            aclfile: None,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            proto_max_multibulk_len: 1024 * 1024,
+            proto_max_nesting: 8,
+            proto_max_line_len: 64 * 1024,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: if TCP_KEEPALIVE_SUPPORTED { 300 } else { 0 },
+            tracking_table_max_keys: 1_000_000,
+        }
+    }
+}
@@ -1,12 +1,23 @@
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::io::Write;
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, bail, ensure, Context};
-use tokio::net::TcpStream;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::acl::{full_command_name, Acl, Denial};
+use crate::client::{self, ClientFilter, ClientInfo, ClientKind, ClientType};
+use crate::commands::{self, CommandFilter, CommandSpec, Handler};
+use crate::data::Data;
+use crate::error::{CommandError, ProtocolError};
+use crate::glob::glob_match;
+use crate::net::PeerAddr;
+use crate::output::{output_channel, ClientOutput, OutputClass, OutputReceiver};
+use crate::pubsub::{PushSender, SubscriptionKind};
 use crate::resp::{RespReader, RespType, RespWriter};
+use crate::tracking::TrackingOptions;
 use crate::Server;
 
 const EMPTY_RDB_FILE: &[u8] = &[
@@ -18,76 +29,1209 @@ const EMPTY_RDB_FILE: &[u8] = &[
     0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
 ];
 
-pub struct Connection<'a> {
-    reader: RespReader<'a>,
-    writer: RespWriter<'a>,
-    addr: SocketAddr,
+/// Size of the output buffer at which replies are written out even if more pipelined commands are waiting
+const OUTPUT_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// How often idle clients are checked against `timeout`
+const IDLE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Resolves once the client has been idle since `since` for longer than `timeout` allows
+async fn idle_timeout(server: &Server, since: Instant) {
+    loop {
+        if server.client_timeout().is_some_and(|timeout| since.elapsed() > timeout) {
+            return;
+        }
+        tokio::time::sleep(IDLE_CHECK_PERIOD).await;
+    }
+}
+
+/// Starts new `INFO` section, separating it from the previous one by an empty line
+fn info_section(buf: &mut Vec<u8>, name: &str) {
+    if !buf.is_empty() {
+        buf.extend_from_slice(b"\n\n");
+    }
+    buf.extend_from_slice(b"# ");
+    buf.extend_from_slice(name.as_bytes());
+}
+
+/// Parses DB index, returning `None` if it's out of range
+fn db_index(arg: &RespType, databases: usize) -> anyhow::Result<Option<usize>> {
+    let index = arg.as_int().context("DB index must be an integer")?;
+    Ok(usize::try_from(index).ok().filter(|index| *index < databases))
+}
+
+/// Commands modifying the data (or publishing messages), which have to be propagated to replicas
+fn is_write_command(spec: &CommandSpec) -> bool {
+    spec.has_flag("write") || spec.has_flag("may-replicate")
+}
+
+/// Keys read by the command, remembered in the invalidation table for client-side caching
+fn read_keys(spec: &CommandSpec, args: &VecDeque<RespType>) -> Vec<Box<[u8]>> {
+    if spec.has_flag("readonly") {
+        spec.keys(args).into_iter().map(|(key, _)| key.into()).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Commands allowed for RESP2 connections subscribed to some channel
+fn is_allowed_in_subscriber_mode(spec: &CommandSpec) -> bool {
+    matches!(
+        spec.handler,
+        Handler::Subscribe
+            | Handler::PSubscribe
+            | Handler::SSubscribe
+            | Handler::Unsubscribe
+            | Handler::PUnsubscribe
+            | Handler::SUnsubscribe
+            | Handler::Ping
+            | Handler::Quit
+            | Handler::Reset
+    )
+}
+
+/// Splits request into command name and its arguments, which all have to be strings
+fn split_command(request: RespType) -> Result<(Bytes, VecDeque<RespType>), ProtocolError> {
+    let mut items = match request {
+        RespType::Array(items) => items,
+        item => VecDeque::from([item]),
+    };
+    if let Some(item) = items.iter().find(|item| !matches!(item, RespType::BulkString(_) | RespType::SimpleString(_))) {
+        let prefix = match item {
+            RespType::SimpleError(_) => '-',
+            RespType::Integer(_) => ':',
+            _ => '*',
+        };
+        return Err(ProtocolError(format!("expected '$', got '{prefix}'")));
+    }
+    let command = match items.pop_front() {
+        Some(RespType::BulkString(name)) => name,
+        Some(RespType::SimpleString(name)) => Bytes::from(name),
+        _ => return Err(ProtocolError(String::from("expected command, got empty array"))),
+    };
+    Ok((command, items))
+}
+
+/// Looks up the command in the command table, checking the number of arguments
+fn lookup_command(command: &[u8], args: &VecDeque<RespType>) -> Result<&'static CommandSpec, CommandError> {
+    let name = String::from_utf8_lossy(command);
+    let Some(spec) = commands::lookup(command) else {
+        let args: String = args
+            .iter()
+            .filter_map(|arg| arg.as_str_bytes().ok())
+            .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+            .collect();
+        return Err(CommandError::UnknownCommand { name: name.into_owned(), args });
+    };
+    if !spec.arity_matches(args.len()) {
+        return Err(CommandError::WrongArity(spec.name.to_string()));
+    }
+    // `COMMAND` is the only container which works without a subcommand
+    if let Some(subcommand) = args.front().filter(|_| spec.is_container()) {
+        let subcommand = subcommand.as_str_bytes().map_err(CommandError::from)?;
+        match spec.subcommand(subcommand) {
+            None => {
+                return Err(CommandError::UnknownSubcommand {
+                    container: spec.name.to_string(),
+                    name: String::from_utf8_lossy(subcommand).into_owned(),
+                })
+            }
+            // Arity of subcommands counts the container name as well
+            Some(sub) if !sub.arity_matches(args.len()) => {
+                return Err(CommandError::WrongArity(sub.name.to_string()));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(spec)
+}
+
+/// Handles `COMMAND` and its subcommands describing the command table
+fn command_introspection(mut args: VecDeque<RespType>) -> anyhow::Result<RespType> {
+    let str = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+    let Some(subcommand) = args.pop_front() else {
+        return Ok(RespType::Array(commands::COMMANDS.iter().map(CommandSpec::info).collect()));
+    };
+    let mut names = Vec::with_capacity(args.len());
+    for arg in &args {
+        names.push(arg.as_str_bytes()?);
+    }
+    let response = match subcommand.as_str_bytes()? {
+        name if name.eq_ignore_ascii_case(b"count") => RespType::Integer(commands::COMMANDS.len() as i64),
+        name if name.eq_ignore_ascii_case(b"info") && names.is_empty() => RespType::Array(commands::COMMANDS.iter().map(CommandSpec::info).collect()),
+        name if name.eq_ignore_ascii_case(b"info") => RespType::Array(
+            names
+                .into_iter()
+                .map(|name| commands::lookup_full(name).map_or(RespType::NullArray, CommandSpec::info))
+                .collect(),
+        ),
+        name if name.eq_ignore_ascii_case(b"docs") => {
+            let specs: Vec<_> = if names.is_empty() {
+                commands::COMMANDS.iter().collect()
+            } else {
+                names.into_iter().filter_map(commands::lookup_full).collect()
+            };
+            RespType::Map(specs.into_iter().map(|spec| (str(spec.name), spec.docs())).collect())
+        }
+        name if name.eq_ignore_ascii_case(b"list") => {
+            let filter = match names.as_slice() {
+                [] => None,
+                [filterby, kind, value] if filterby.eq_ignore_ascii_case(b"filterby") => {
+                    match kind {
+                        _ if kind.eq_ignore_ascii_case(b"module") => Some(CommandFilter::Module),
+                        _ if kind.eq_ignore_ascii_case(b"aclcat") => Some(CommandFilter::AclCategory(String::from_utf8_lossy(value).into_owned())),
+                        _ if kind.eq_ignore_ascii_case(b"pattern") => Some(CommandFilter::Pattern(Box::from(*value))),
+                        _ => bail!("Unknown filter `{}` for `COMMAND LIST`", String::from_utf8_lossy(kind)),
+                    }
+                }
+                _ => bail!("Invalid arguments for `COMMAND LIST`, expected FILTERBY <MODULE|ACLCAT|PATTERN> <value>"),
+            };
+            RespType::Array(
+                commands::all_commands()
+                    .filter(|spec| filter.as_ref().is_none_or(|filter| filter.matches(spec)))
+                    .map(|spec| str(spec.name))
+                    .collect(),
+            )
+        }
+        name if name.eq_ignore_ascii_case(b"getkeys") => {
+            let command = args.pop_front().unwrap();
+            match commands::lookup(command.as_str_bytes()?) {
+                None => CommandError::err("Invalid command specified").into(),
+                Some(spec) if !spec.arity_matches(args.len()) => {
+                    CommandError::err("Invalid number of arguments specified for command").into()
+                }
+                Some(spec) => {
+                    let keys = spec.keys(&args);
+                    if keys.is_empty() {
+                        CommandError::err("The command has no key arguments").into()
+                    } else {
+                        RespType::Array(keys.into_iter().map(|(key, _)| RespType::bulk_string_from_bytes(key)).collect())
+                    }
+                }
+            }
+        }
+        subcommand => bail!("Unknown subcommand `{}` for `COMMAND` command", String::from_utf8_lossy(subcommand)),
+    };
+    Ok(response)
+}
+
+/// Commands queued after `MULTI`, executed by `EXEC`
+#[derive(Debug, Default)]
+struct Transaction {
+    commands: Vec<(&'static CommandSpec, VecDeque<RespType>)>,
+    /// Set when queueing some command failed, `EXEC` then discards the whole transaction
+    aborted: bool,
+}
+
+pub struct Connection<R, W> {
+    reader: RespReader<R>,
+    writer: RespWriter<W>,
+    addr: PeerAddr,
+    /// Our side of the connection
+    laddr: PeerAddr,
     server: Server,
+    kind: ClientKind,
+    /// Set by `CLIENT SETNAME`
+    name: String,
+    /// Set by `CLIENT SETINFO`
+    lib_name: String,
+    lib_ver: String,
+    created: Instant,
+    /// When the last command was received
+    last_interaction: Instant,
+    /// Full name of the last command, shown by `CLIENT LIST`
+    last_command: String,
+    /// Set by `CLIENT NO-EVICT ON`, there's no client eviction so it's only reported
+    no_evict: bool,
+    /// Currently selected database
+    db: usize,
+    /// Set by `PSYNC`, switches the connection to streaming replication once the command is done
+    replication_stream: Option<OutputReceiver<Arc<[u8]>>>,
+    /// Set by `MULTI`, commands are queued instead of executed until `EXEC` or `DISCARD`
+    transaction: Option<Transaction>,
+    /// Keys watched by `WATCH` as database index, key and its version at the time
+    watched: Vec<(usize, Box<[u8]>, u64)>,
+    id: u64,
+    /// Whether the client switched to RESP3 by `HELLO 3`
+    resp3: bool,
+    /// Messages delivered to this connection out of band, e.g. pub/sub messages
+    push_tx: PushSender,
+    push_rx: OutputReceiver<RespType>,
+    /// Output waiting to be written to the client, limited by `client-output-buffer-limit`
+    output: Arc<ClientOutput>,
+    channels: HashSet<Box<[u8]>>,
+    patterns: HashSet<Box<[u8]>>,
+    shard_channels: HashSet<Box<[u8]>>,
+    /// Set by `CLIENT TRACKING ON`, options of client-side caching
+    tracking: Option<TrackingOptions>,
+    /// Set by `CLIENT CACHING`, applies to the next command (or transaction) only
+    caching: Option<bool>,
+    /// Cleared when the server requires password, set by successful `AUTH`
+    authenticated: bool,
+    /// ACL user the connection is authenticated as
+    user: String,
+    /// Set by `QUIT`, the connection is closed once the reply is sent
+    quit: bool,
 }
 
-impl<'a> Connection<'a> {
-    pub fn new(stream: &'a mut TcpStream, addr: SocketAddr, server: Server) -> Self {
-        let (reader, writer) = stream.split();
-        Self {
-            reader: RespReader::new(reader),
-            writer: RespWriter::new(writer),
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
+    /// Client connected through TCP or Unix socket, `laddr` being our end of it
+    pub fn new(reader: R, writer: W, addr: PeerAddr, laddr: PeerAddr, server: Server) -> Self {
+        let reader = RespReader::new(reader, Arc::clone(&server.proto_limits));
+        Self::from_parts(reader, RespWriter::new(writer), addr, laddr, server, ClientKind::Normal)
+    }
+
+    /// Connection to our master, applying its write commands after the replication handshake
+    pub(crate) fn master_link(reader: RespReader<R>, writer: RespWriter<W>, addr: PeerAddr, laddr: PeerAddr, server: Server) -> Self {
+        Self::from_parts(reader, writer, addr, laddr, server, ClientKind::Master)
+    }
+
+    fn from_parts(reader: RespReader<R>, writer: RespWriter<W>, addr: PeerAddr, laddr: PeerAddr, server: Server, kind: ClientKind) -> Self {
+        let id = server.next_client_id();
+        let output = server.client_output(format!("id={id} addr={addr}"));
+        let (push_tx, push_rx) = output_channel(&output);
+        // Our master is trusted, we authenticated with it instead
+        let authenticated = kind == ClientKind::Master || !server.requires_auth();
+        let now = Instant::now();
+        let conn = Self {
+            id,
+            reader,
+            writer,
             addr,
+            laddr,
             server,
-        }
+            kind,
+            name: String::new(),
+            lib_name: String::new(),
+            lib_ver: String::new(),
+            created: now,
+            last_interaction: now,
+            last_command: String::from("NULL"),
+            no_evict: false,
+            db: 0,
+            replication_stream: None,
+            transaction: None,
+            watched: Vec::new(),
+            resp3: false,
+            push_tx,
+            push_rx,
+            output,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            tracking: None,
+            caching: None,
+            authenticated,
+            user: String::from("default"),
+            quit: false,
+        };
+        conn.server.register_client(conn.client_info(), conn.push_tx.clone(), Arc::clone(&conn.output));
+        conn
     }
 
-    /// `command` must be lowercase!
-    async fn command(
-        &mut self,
-        command: &[u8],
-        mut args: VecDeque<RespType>,
-    ) -> anyhow::Result<()> {
+    fn subscriptions_count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    async fn command(&mut self, command: &[u8], args: VecDeque<RespType>) -> anyhow::Result<()> {
+        self.last_interaction = Instant::now();
+        // Arguments may contain passwords (`AUTH`, `CONFIG SET requirepass`...), so they are never logged
         eprintln!(
-            "Processing command {} with args {args:?}",
-            String::from_utf8_lossy(command)
+            "Processing command {} with {} args",
+            String::from_utf8_lossy(command),
+            args.len()
         );
-        match command {
-            b"ping" => {
-                ensure!(args.is_empty(), "PING accepts no args!");
-                let response = RespType::SimpleString(String::from("PONG"));
-                self.writer.write_item(response).await?;
-            }
-            b"echo" => {
-                ensure!(args.len() == 1, "ECHO accepts exactly one arg!");
-                let response = match args.pop_front().unwrap() {
-                    arg @ RespType::BulkString(_) => arg,
-                    _ => bail!("Invalid argument for `ECHO` command"),
+        let spec = match lookup_command(command, &args) {
+            Ok(spec) => spec,
+            Err(err) => {
+                self.last_command = String::from_utf8_lossy(command).to_ascii_lowercase();
+                if let Some(transaction) = &mut self.transaction {
+                    transaction.aborted = true;
+                }
+                let result = self.reply(err.into()).await;
+                self.server.update_client(self.client_info());
+                return result;
+            }
+        };
+        self.last_command = full_command_name(spec, &args);
+        let result = match self.dispatch(spec, args).await {
+            // Failed command is replied with an error, only a broken connection closes it
+            Err(err) if err.downcast_ref::<std::io::Error>().is_none() => {
+                self.reply(CommandError::from(err).into()).await
+            }
+            result => result,
+        };
+        // `CLIENT CACHING` applies to the next command, or to the whole transaction
+        if spec.handler != Handler::Client && self.transaction.is_none() {
+            self.caching = None;
+        }
+        self.server.update_client(self.client_info());
+        result
+    }
+
+    async fn dispatch(&mut self, spec: &'static CommandSpec, args: VecDeque<RespType>) -> anyhow::Result<()> {
+        if !self.authenticated && !spec.has_flag("no-auth") {
+            return self.reply(CommandError::NoAuth.into()).await;
+        }
+        // Our master may run anything, the replicated commands were already checked there
+        if self.kind != ClientKind::Master {
+            let denial = match self.server.acl().user(&self.user) {
+                Some(user) => user.check(spec, &args).err(),
+                None => Some(Denial::Command(full_command_name(spec, &args))),
+            };
+            if let Some(denial) = denial {
+                let client_info = self.client_info().to_string();
+                self.server.acl().log_denial(&denial, &self.user, self.transaction.is_some(), client_info);
+                if let Some(transaction) = &mut self.transaction {
+                    transaction.aborted = true;
+                }
+                return self.reply(denial.error(&self.user).into()).await;
+            }
+        }
+        // Replicas only apply writes coming from their master
+        if self.kind != ClientKind::Master
+            && spec.has_flag("write")
+            && matches!(self.server.replication, crate::ReplicationMode::Slave { .. })
+        {
+            if let Some(transaction) = &mut self.transaction {
+                transaction.aborted = true;
+            }
+            return self.reply(CommandError::ReadOnly.into()).await;
+        }
+        if !self.resp3 && self.subscriptions_count() > 0 && !is_allowed_in_subscriber_mode(spec) {
+            let response = CommandError::err(format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                spec.name
+            ));
+            return self.reply(response.into()).await;
+        }
+        // Our master's writes are applied even while clients are paused, and pause can always be lifted
+        if self.kind == ClientKind::Normal && !spec.resolve(&args).pause_exempt {
+            let write = is_write_command(spec)
+                || spec.handler == Handler::Exec
+                    && self.transaction.as_ref().is_some_and(|transaction| {
+                        transaction.commands.iter().any(|(spec, _)| is_write_command(spec))
+                    });
+            // Replies to the commands pipelined before must not wait for the pause to end
+            if self.server.is_paused(write) {
+                self.flush().await?;
+                self.server.wait_unpaused(write).await;
+            }
+        }
+        match spec.handler {
+            Handler::Quit => {
+                self.quit = true;
+                return self.reply(RespType::SimpleString(String::from("OK"))).await;
+            }
+            Handler::Reset => {
+                self.reset().await;
+                return self.reply(RespType::SimpleString(String::from("RESET"))).await;
+            }
+            Handler::Multi => {
+                let response = if self.transaction.is_some() {
+                    CommandError::err("MULTI calls can not be nested").into()
+                } else {
+                    self.transaction = Some(Transaction::default());
+                    RespType::SimpleString(String::from("OK"))
                 };
-                self.writer.write_item(response).await?;
+                return self.reply(response).await;
             }
-            b"get" => {
-                ensure!(args.len() == 1, "GET accepts exactly one arg!");
-                let key = match args.pop_front().unwrap() {
-                    RespType::BulkString(s) => s,
-                    _ => bail!("Invalid value for `key` argument"),
+            Handler::Exec => return self.exec().await,
+            Handler::Discard => {
+                let response = match self.transaction.take() {
+                    Some(_) => {
+                        self.unwatch_all(&mut self.server.clone().data().await);
+                        RespType::SimpleString(String::from("OK"))
+                    }
+                    None => CommandError::err("DISCARD without MULTI").into(),
                 };
-                let value = self.server.get(key).await;
-                let response = match value {
-                    Some(value) => RespType::BulkString(value),
+                return self.reply(response).await;
+            }
+            _ if self.transaction.is_some() => return self.queue(spec, args).await,
+            Handler::PSync => return self.psync(args).await,
+            Handler::Subscribe
+            | Handler::PSubscribe
+            | Handler::SSubscribe
+            | Handler::Unsubscribe
+            | Handler::PUnsubscribe
+            | Handler::SUnsubscribe => {
+                return self.subscription(spec, args).await;
+            }
+            _ => {}
+        }
+
+        // The guard must not borrow `self`, `execute` needs it mutably
+        let server = self.server.clone();
+        let mut dbs = server.data().await;
+        let response = self
+            .execute_and_propagate(&server, &mut dbs, spec, args)
+            .unwrap_or_else(|err| CommandError::from(err).into());
+        drop(dbs);
+        server.send_invalidations(Some(self.id));
+        self.reply(response).await
+    }
+
+    /// Validates the command and adds it to the transaction started by `MULTI`
+    async fn queue(&mut self, spec: &'static CommandSpec, args: VecDeque<RespType>) -> anyhow::Result<()> {
+        let transaction = self.transaction.as_mut().expect("Queueing only happens inside MULTI");
+        let response = if spec.handler == Handler::Watch {
+            transaction.aborted = true;
+            CommandError::err("WATCH inside MULTI is not allowed").into()
+        } else if spec.has_flag("no-multi") {
+            transaction.aborted = true;
+            CommandError::err("Command not allowed inside a transaction").into()
+        } else {
+            transaction.commands.push((spec, args));
+            RespType::SimpleString(String::from("QUEUED"))
+        };
+        self.reply(response).await
+    }
+
+    fn subscriptions(&mut self, kind: SubscriptionKind) -> &mut HashSet<Box<[u8]>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Handles `(P|S)SUBSCRIBE` and `(P|S)UNSUBSCRIBE`, replying with one push per channel
+    async fn subscription(&mut self, spec: &CommandSpec, args: VecDeque<RespType>) -> anyhow::Result<()> {
+        let names = args
+            .into_iter()
+            .map(|arg| arg.into_bulk_string().map(|name| Box::from(&*name)).context("Invalid value for `channel` argument"))
+            .collect::<anyhow::Result<Vec<Box<[u8]>>>>()?;
+        let kind = match spec.handler {
+            Handler::PSubscribe | Handler::PUnsubscribe => SubscriptionKind::Pattern,
+            Handler::SSubscribe | Handler::SUnsubscribe => SubscriptionKind::Shard,
+            _ => SubscriptionKind::Channel,
+        };
+        let subscribe = matches!(spec.handler, Handler::Subscribe | Handler::PSubscribe | Handler::SSubscribe);
+        // Reply type is the command name
+        let reply_type = RespType::bulk_string_from_bytes(spec.name.as_bytes());
+        // Sharded subscriptions are counted separately from the classic ones
+        let count = |conn: &Self| match kind {
+            SubscriptionKind::Shard => conn.shard_channels.len(),
+            _ => conn.channels.len() + conn.patterns.len(),
+        } as i64;
+
+        let names = if !subscribe && names.is_empty() {
+            self.subscriptions(kind).iter().cloned().collect()
+        } else {
+            names
+        };
+        let server = self.server.clone();
+        let mut responses = Vec::with_capacity(names.len());
+        for name in names {
+            let changed = if subscribe {
+                self.subscriptions(kind).insert(name.clone())
+            } else {
+                self.subscriptions(kind).remove(&name)
+            };
+            if changed && subscribe {
+                server.pubsub().subscribe(kind, &name, self.id, &self.push_tx);
+            } else if changed {
+                server.pubsub().unsubscribe(kind, &name, self.id);
+            }
+            responses.push(RespType::Push(VecDeque::from([
+                reply_type.clone(),
+                RespType::BulkString(name.into()),
+                RespType::Integer(count(self)),
+            ])));
+        }
+        self.update_output_class();
+        if responses.is_empty() {
+            // Unsubscribing from everything while not subscribed to anything
+            responses.push(RespType::Push(VecDeque::from([
+                reply_type,
+                RespType::NullBulkString,
+                RespType::Integer(count(self)),
+            ])));
+        }
+        for response in responses {
+            self.reply(response).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribed clients fall under the `pubsub` class of output buffer limits
+    fn update_output_class(&self) {
+        if self.kind != ClientKind::Replica {
+            let class = if self.subscriptions_count() > 0 { OutputClass::PubSub } else { OutputClass::Normal };
+            self.output.set_class(class);
+        }
+    }
+
+    fn unsubscribe_all(&mut self) {
+        let mut pubsub = self.server.pubsub();
+        for channel in self.channels.drain() {
+            pubsub.unsubscribe(SubscriptionKind::Channel, &channel, self.id);
+        }
+        for pattern in self.patterns.drain() {
+            pubsub.unsubscribe(SubscriptionKind::Pattern, &pattern, self.id);
+        }
+        for channel in self.shard_channels.drain() {
+            pubsub.unsubscribe(SubscriptionKind::Shard, &channel, self.id);
+        }
+    }
+
+    /// Returns the connection to its initial state, as requested by `RESET`
+    async fn reset(&mut self) {
+        self.transaction = None;
+        self.unsubscribe_all();
+        self.update_output_class();
+        if !self.watched.is_empty() {
+            self.unwatch_all(&mut self.server.clone().data().await);
+        }
+        self.db = 0;
+        self.resp3 = false;
+        self.writer.set_resp3(false);
+        self.server.set_client_resp3(self.id, false);
+        if self.tracking.take().is_some() {
+            self.server.tracking().disable(self.id);
+        }
+        self.caching = None;
+        self.no_evict = false;
+        self.user = String::from("default");
+        self.authenticated = !self.server.requires_auth();
+    }
+
+    /// Runs all queued commands while holding the data lock, replying with array of their replies
+    async fn exec(&mut self) -> anyhow::Result<()> {
+        let Some(transaction) = self.transaction.take() else {
+            return self.reply(CommandError::err("EXEC without MULTI").into()).await;
+        };
+        let server = self.server.clone();
+        let mut dbs = server.data().await;
+        let watched_modified = self
+            .watched
+            .iter()
+            .any(|(db, key, version)| dbs[*db].is_modified_since(key, *version));
+        self.unwatch_all(&mut dbs);
+        if transaction.aborted {
+            drop(dbs);
+            return self.reply(CommandError::ExecAbort.into()).await;
+        }
+        if watched_modified {
+            drop(dbs);
+            return self.reply(RespType::NullArray).await;
+        }
+        let has_writes = server.has_replicas()
+            && transaction.commands.iter().any(|(spec, _)| is_write_command(spec));
+        if has_writes {
+            // Replicas must apply the writes atomically as well
+            server.propagate(self.db, b"multi", VecDeque::new());
+        }
+        let mut responses = VecDeque::with_capacity(transaction.commands.len());
+        for (spec, args) in transaction.commands {
+            // Failure of single command doesn't stop the others, same as in Redis
+            let response = self
+                .execute_and_propagate(&server, &mut dbs, spec, args)
+                .unwrap_or_else(|err| CommandError::from(err).into());
+            responses.push_back(response);
+        }
+        if has_writes {
+            server.propagate(self.db, b"exec", VecDeque::new());
+        }
+        drop(dbs);
+        server.send_invalidations(Some(self.id));
+        self.reply(RespType::Array(responses)).await
+    }
+
+    fn unwatch_all(&mut self, dbs: &mut [Data]) {
+        for (db, key, _) in self.watched.drain(..) {
+            dbs[db].unwatch(&key);
+        }
+    }
+
+    /// Snapshot of the connection for the client registry, also describing the client in `ACL LOG`
+    fn client_info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            addr: self.addr.clone(),
+            laddr: self.laddr.clone(),
+            name: self.name.clone(),
+            created: self.created,
+            last_interaction: self.last_interaction,
+            kind: self.kind,
+            db: self.db,
+            subscriptions: self.channels.len(),
+            pattern_subscriptions: self.patterns.len(),
+            shard_subscriptions: self.shard_channels.len(),
+            multi: self.transaction.as_ref().map_or(-1, |transaction| transaction.commands.len() as i64),
+            watch: self.watched.len(),
+            query_buffer: self.reader.buffered_len(),
+            output_buffer: self.writer.buffered_len(),
+            output_memory: self.output.len(),
+            last_command: self.last_command.clone(),
+            user: self.user.clone(),
+            redirect: match &self.tracking {
+                Some(options) => options.redirect.map_or(0, |id| id as i64),
+                None => -1,
+            },
+            resp: if self.resp3 { 3 } else { 2 },
+            no_evict: self.no_evict,
+            closing: self.output.is_closing(),
+            lib_name: self.lib_name.clone(),
+            lib_ver: self.lib_ver.clone(),
+        }
+    }
+
+    fn authenticate(&mut self, username: &[u8], password: &[u8]) -> RespType {
+        let username = String::from_utf8_lossy(username);
+        let password = String::from_utf8_lossy(password);
+        if self.server.acl().authenticate(&username, &password) {
+            self.authenticated = true;
+            self.user = username.into_owned();
+            RespType::SimpleString(String::from("OK"))
+        } else {
+            let client_info = self.client_info().to_string();
+            self.server.acl().log_denial(&Denial::Auth, &username, self.transaction.is_some(), client_info);
+            Denial::Auth.error(&username).into()
+        }
+    }
+
+    /// Handles `CLIENT` subcommands
+    fn client(&mut self, mut args: VecDeque<RespType>) -> anyhow::Result<RespType> {
+        let subcommand = args.pop_front().context("Missing CLIENT subcommand")?;
+        let ok = || RespType::SimpleString(String::from("OK"));
+        let response = match subcommand.as_str_bytes()? {
+            name if name.eq_ignore_ascii_case(b"tracking") => self.client_tracking(args)?,
+            name if name.eq_ignore_ascii_case(b"caching") => {
+                let mode = args.pop_front().unwrap();
+                let yes = match mode.as_str_bytes()? {
+                    name if name.eq_ignore_ascii_case(b"yes") => true,
+                    name if name.eq_ignore_ascii_case(b"no") => false,
+                    _ => bail!("Invalid CLIENT CACHING mode, expected YES or NO"),
+                };
+                match &self.tracking {
+                    Some(options) if yes && options.optin || !yes && options.optout => {
+                        self.caching = Some(yes);
+                        RespType::SimpleString(String::from("OK"))
+                    }
+                    Some(options) if options.optin || options.optout => CommandError::err(format!("CLIENT CACHING {} is only valid when tracking is enabled in {} mode.",
+                        if yes { "YES" } else { "NO" },
+                        if yes { "OPTIN" } else { "OPTOUT" }
+                    ))
+                    .into(),
+                    _ => CommandError::err("CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled").into(),
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"getredir") => {
+                let redirect = match &self.tracking {
+                    Some(options) => options.redirect.map_or(0, |id| id as i64),
+                    None => -1,
+                };
+                RespType::Integer(redirect)
+            }
+            name if name.eq_ignore_ascii_case(b"id") => RespType::Integer(self.id as i64),
+            name if name.eq_ignore_ascii_case(b"info") => RespType::bulk_string_from_string(format!("{}\n", self.client_info())),
+            name if name.eq_ignore_ascii_case(b"list") => {
+                let filter = self.client_filter(args, true)?;
+                let mut list = String::new();
+                for mut info in self.server.client_list(&filter) {
+                    // Our own entry is only refreshed once the command is done
+                    if info.id == self.id {
+                        info = self.client_info();
+                    }
+                    list.push_str(&format!("{info}\n"));
+                }
+                RespType::bulk_string_from_string(list)
+            }
+            name if name.eq_ignore_ascii_case(b"kill") => {
+                // Old form `CLIENT KILL addr:port` kills also the caller and replies OK
+                let legacy = args.len() == 1;
+                let mut filter = if legacy {
+                    ClientFilter {
+                        addr: Some(String::from_utf8_lossy(args[0].as_str_bytes()?).into_owned()),
+                        ..ClientFilter::default()
+                    }
+                } else {
+                    self.client_filter(args, false)?
+                };
+                // The caller is closed once the reply is written, not right away
+                let kill_self = filter.matches(&self.client_info());
+                filter.skip = Some(self.id);
+                let killed = self.server.kill_clients(&filter) + usize::from(kill_self);
+                self.quit |= kill_self;
+                match killed {
+                    0 if legacy => CommandError::err("No such client").into(),
+                    _ if legacy => ok(),
+                    killed => RespType::Integer(killed as i64),
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"getname") => match self.name.as_str() {
+                "" => RespType::NullBulkString,
+                name => RespType::bulk_string_from_bytes(name.as_bytes()),
+            },
+            name if name.eq_ignore_ascii_case(b"setname") => {
+                let name = args.pop_front().unwrap();
+                let name = name.as_str_bytes()?;
+                if !client::is_valid_name(name) {
+                    return Ok(CommandError::err("Client names cannot contain spaces, newlines or special characters.").into());
+                }
+                self.name = String::from_utf8_lossy(name).into_owned();
+                ok()
+            }
+            name if name.eq_ignore_ascii_case(b"setinfo") => {
+                let attribute = args.pop_front().unwrap();
+                let value = args.pop_front().unwrap();
+                let value = value.as_str_bytes()?;
+                let target = match attribute.as_str_bytes()? {
+                    name if name.eq_ignore_ascii_case(b"lib-name") => &mut self.lib_name,
+                    name if name.eq_ignore_ascii_case(b"lib-ver") => &mut self.lib_ver,
+                    attribute => {
+                        let attribute = String::from_utf8_lossy(attribute).into_owned();
+                        return Ok(CommandError::err(format!("Unrecognized option '{attribute}'")).into());
+                    }
+                };
+                if !client::is_valid_name(value) {
+                    let attribute = String::from_utf8_lossy(attribute.as_str_bytes()?).into_owned();
+                    return Ok(CommandError::err(format!("{attribute} cannot contain spaces, newlines or special characters.")).into());
+                }
+                *target = String::from_utf8_lossy(value).into_owned();
+                ok()
+            }
+            name if name.eq_ignore_ascii_case(b"no-evict") => {
+                let mode = args.pop_front().unwrap();
+                self.no_evict = match mode.as_str_bytes()? {
+                    name if name.eq_ignore_ascii_case(b"on") => true,
+                    name if name.eq_ignore_ascii_case(b"off") => false,
+                    _ => bail!("syntax error"),
+                };
+                ok()
+            }
+            name if name.eq_ignore_ascii_case(b"pause") => {
+                let timeout = args.pop_front().unwrap();
+                let timeout = timeout.as_int().ok().context("timeout is not an integer or out of range")?;
+                ensure!(timeout >= 0, "timeout is negative");
+                // Redis keeps the deadline in milliseconds since the epoch, which has to fit in `i64`
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+                let until = (timeout <= i64::MAX - now)
+                    .then(|| Instant::now().checked_add(Duration::from_millis(timeout as u64)))
+                    .flatten()
+                    .context("timeout is out of range")?;
+                let all = match args.pop_front() {
+                    None => true,
+                    Some(mode) => match mode.as_str_bytes()? {
+                        name if name.eq_ignore_ascii_case(b"all") => true,
+                        name if name.eq_ignore_ascii_case(b"write") => false,
+                        _ => bail!("syntax error"),
+                    },
+                };
+                ensure!(args.is_empty(), "syntax error");
+                self.server.pause_clients(until, all);
+                ok()
+            }
+            name if name.eq_ignore_ascii_case(b"unpause") => {
+                self.server.unpause_clients();
+                ok()
+            }
+            subcommand => bail!("Unknown subcommand `{}` for `CLIENT` command", String::from_utf8_lossy(subcommand)),
+        };
+        Ok(response)
+    }
+
+    /// Parses filters of `CLIENT LIST` (`list` is set) or of the new form of `CLIENT KILL`
+    fn client_filter(&self, mut args: VecDeque<RespType>, list: bool) -> anyhow::Result<ClientFilter> {
+        let mut filter = ClientFilter {
+            // `CLIENT KILL` skips the caller by default
+            skip: (!list).then_some(self.id),
+            ..ClientFilter::default()
+        };
+        let parse_id = |arg: &RespType| {
+            arg.as_int()
+                .ok()
+                .and_then(|id| u64::try_from(id).ok())
+                .filter(|id| *id > 0)
+                .context("client-id should be greater than 0")
+        };
+        while let Some(option) = args.pop_front() {
+            let option = option.as_str_bytes()?;
+            // `CLIENT LIST ID` takes all the remaining args
+            if list && option.eq_ignore_ascii_case(b"id") {
+                ensure!(!args.is_empty(), "syntax error");
+                let ids = args.drain(..).map(|arg| parse_id(&arg)).collect::<anyhow::Result<_>>()?;
+                filter.ids = Some(ids);
+                break;
+            }
+            let value = args.pop_front().context("syntax error")?;
+            let value = String::from_utf8_lossy(value.as_str_bytes()?).into_owned();
+            match option {
+                _ if option.eq_ignore_ascii_case(b"type") => filter.client_type = Some(ClientType::parse(&value)?),
+                _ if !list && option.eq_ignore_ascii_case(b"id") => filter.ids = Some(vec![parse_id(&RespType::bulk_string_from_string(value))?]),
+                _ if !list && option.eq_ignore_ascii_case(b"addr") => filter.addr = Some(value),
+                _ if !list && option.eq_ignore_ascii_case(b"laddr") => filter.laddr = Some(value),
+                _ if !list && option.eq_ignore_ascii_case(b"user") => {
+                    ensure!(self.server.acl().user(&value).is_some(), "No such user '{value}'");
+                    filter.user = Some(value);
+                }
+                _ if !list && option.eq_ignore_ascii_case(b"skipme") => {
+                    filter.skip = match value {
+                        _ if value.eq_ignore_ascii_case("yes") => Some(self.id),
+                        _ if value.eq_ignore_ascii_case("no") => None,
+                        _ => bail!("syntax error"),
+                    };
+                }
+                _ if !list && option.eq_ignore_ascii_case(b"maxage") => {
+                    let seconds = value.parse().context("syntax error")?;
+                    filter.max_age = Some(Duration::from_secs(seconds));
+                }
+                // Not in Redis, lets `CLIENT KILL ADDR addr IDLE secs` close the client only if it's stuck
+                _ if !list && option.eq_ignore_ascii_case(b"idle") => {
+                    let seconds = value.parse().context("syntax error")?;
+                    filter.idle = Some(Duration::from_secs(seconds));
+                }
+                _ => bail!("syntax error"),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Handles `ACL` subcommands
+    fn acl(&mut self, mut args: VecDeque<RespType>) -> anyhow::Result<RespType> {
+        let subcommand = args.pop_front().context("Missing ACL subcommand")?;
+        let mut strings = Vec::with_capacity(args.len());
+        for arg in &args {
+            strings.push(String::from_utf8_lossy(arg.as_str_bytes()?).into_owned());
+        }
+        let bulk = |s: &str| RespType::bulk_string_from_bytes(s.as_bytes());
+        let ok = || RespType::SimpleString(String::from("OK"));
+        let response = match subcommand.as_str_bytes()? {
+            name if name.eq_ignore_ascii_case(b"setuser") => {
+                let name = strings.first().context("Missing user name for `ACL SETUSER`")?;
+                match self.server.acl().set_user(name, &strings[1..]) {
+                    Ok(()) => ok(),
+                    Err(err) => CommandError::Err(err.to_string()).into(),
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"getuser") => {
+                ensure!(strings.len() == 1, "ACL GETUSER requires exactly one arg!");
+                match self.server.acl().user(&strings[0]) {
+                    Some(user) => user.to_resp(),
                     None => RespType::NullBulkString,
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"deluser") => {
+                ensure!(!strings.is_empty(), "ACL DELUSER requires at least one arg!");
+                let mut deleted = Vec::new();
+                {
+                    let mut acl = self.server.acl();
+                    for name in &strings {
+                        match acl.del_user(name) {
+                            Ok(true) => deleted.push(name),
+                            Ok(false) => {}
+                            Err(err) => return Ok(CommandError::Err(err.to_string()).into()),
+                        }
+                    }
+                }
+                // Clients authenticated as the deleted users are disconnected, the caller once the reply is written
+                for name in &deleted {
+                    let mut filter = ClientFilter { user: Some(name.to_string()), ..ClientFilter::default() };
+                    self.quit |= filter.matches(&self.client_info());
+                    filter.skip = Some(self.id);
+                    self.server.kill_clients(&filter);
+                }
+                RespType::Integer(deleted.len() as i64)
+            }
+            name if name.eq_ignore_ascii_case(b"list") => RespType::Array(self.server.acl().list().iter().map(|line| bulk(line)).collect()),
+            name if name.eq_ignore_ascii_case(b"users") => RespType::Array(self.server.acl().usernames().into_iter().map(bulk).collect()),
+            name if name.eq_ignore_ascii_case(b"whoami") => bulk(&self.user),
+            name if name.eq_ignore_ascii_case(b"cat") => {
+                ensure!(strings.len() <= 1, "ACL CAT accepts at most one arg!");
+                match Acl::categories(strings.first().map(String::as_str)) {
+                    Some(names) => RespType::Array(names.into_iter().map(bulk).collect()),
+                    None => CommandError::err(format!("Unknown category '{}'", strings[0])).into(),
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"log") => {
+                ensure!(strings.len() <= 1, "ACL LOG accepts at most one arg!");
+                match strings.first() {
+                    Some(arg) if arg.eq_ignore_ascii_case("reset") => {
+                        self.server.acl().reset_log();
+                        ok()
+                    }
+                    Some(arg) => {
+                        let count = arg.parse().context("Value of `count` arg must be a non-negative integer")?;
+                        self.server.acl().log_entries(count)
+                    }
+                    None => self.server.acl().log_entries(10),
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"dryrun") => {
+                ensure!(strings.len() >= 2, "ACL DRYRUN requires user name and command!");
+                args.pop_front();
+                let command = args.pop_front().unwrap();
+                let command = command.as_str_bytes()?;
+                let acl = self.server.acl();
+                match (acl.user(&strings[0]), commands::lookup(command)) {
+                    (None, _) => CommandError::err(format!("User '{}' not found", strings[0])).into(),
+                    (_, None) => CommandError::err(format!("Command '{}' not found", strings[1])).into(),
+                    (Some(user), Some(spec)) => match user.check(spec, &args) {
+                        Ok(()) => ok(),
+                        Err(denial) => bulk(&denial.explain(&strings[0])),
+                    },
+                }
+            }
+            name if name.eq_ignore_ascii_case(b"load") => match self.server.load_acl_file() {
+                Ok(()) => ok(),
+                Err(err) => CommandError::Err(err.to_string()).into(),
+            },
+            name if name.eq_ignore_ascii_case(b"save") => match self.server.save_acl_file() {
+                Ok(()) => ok(),
+                Err(err) => CommandError::Err(err.to_string()).into(),
+            },
+            subcommand => bail!("Unknown subcommand `{}` for `ACL` command", String::from_utf8_lossy(subcommand)),
+        };
+        Ok(response)
+    }
+
+    /// Whether keys read by the current command should be remembered for client-side caching
+    fn tracks_reads(&self) -> bool {
+        match &self.tracking {
+            Some(options) if options.bcast => false,
+            Some(options) if options.optin => self.caching == Some(true),
+            Some(options) if options.optout => self.caching != Some(false),
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Handles `CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]`
+    fn client_tracking(&mut self, mut args: VecDeque<RespType>) -> anyhow::Result<RespType> {
+        let state = args.pop_front().context("Missing CLIENT TRACKING state")?;
+        let enable = match state.as_str_bytes()? {
+            name if name.eq_ignore_ascii_case(b"on") => true,
+            name if name.eq_ignore_ascii_case(b"off") => false,
+            _ => bail!("Invalid CLIENT TRACKING state, expected ON or OFF"),
+        };
+        let mut options = TrackingOptions::default();
+        while let Some(arg) = args.pop_front() {
+            match arg.as_str_bytes()? {
+                name if name.eq_ignore_ascii_case(b"redirect") => {
+                    let id = args.pop_front().context("Missing value for `redirect` arg")?;
+                    let id = id.as_int().ok().and_then(|id| u64::try_from(id).ok()).context("Invalid client ID")?;
+                    options.redirect = Some(id);
+                }
+                name if name.eq_ignore_ascii_case(b"prefix") => {
+                    let prefix = args.pop_front().context("Missing value for `prefix` arg")?;
+                    options.prefixes.push(Box::from(&*prefix.into_bulk_string()?));
+                }
+                name if name.eq_ignore_ascii_case(b"bcast") => options.bcast = true,
+                name if name.eq_ignore_ascii_case(b"optin") => options.optin = true,
+                name if name.eq_ignore_ascii_case(b"optout") => options.optout = true,
+                name if name.eq_ignore_ascii_case(b"noloop") => options.noloop = true,
+                argname => bail!("Unknown parameter `{}` for `CLIENT TRACKING` command", String::from_utf8_lossy(argname)),
+            }
+        }
+
+        if !enable {
+            if self.tracking.take().is_some() {
+                self.server.tracking().disable(self.id);
+            }
+            self.caching = None;
+            return Ok(RespType::SimpleString(String::from("OK")));
+        }
+        let error = |message: &str| Ok(CommandError::err(message).into());
+        if !options.prefixes.is_empty() && !options.bcast {
+            return error("PREFIX option requires BCAST mode to be enabled");
+        }
+        if options.optin && options.optout {
+            return error("You can't use both OPTIN and OPTOUT");
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return error("OPTIN and OPTOUT are not compatible with BCAST");
+        }
+        if let Some(current) = &self.tracking {
+            if current.bcast != options.bcast {
+                return error("You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.");
+            }
+            if current.optin != options.optin || current.optout != options.optout {
+                return error("You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.");
+            }
+        }
+        if let Some(redirect) = options.redirect {
+            if redirect == self.id || !self.server.client_exists(redirect) {
+                return error("The client ID you want redirect to does not exist");
+            }
+        }
+        if let Err(err) = self.server.tracking().enable(self.id, options.clone()) {
+            return error(&err);
+        }
+        self.tracking = Some(options);
+        Ok(RespType::SimpleString(String::from("OK")))
+    }
+
+    /// Executes the command, propagating it to replicas if it modified the data
+    fn execute_and_propagate(
+        &mut self,
+        server: &Server,
+        dbs: &mut [Data],
+        spec: &CommandSpec,
+        args: VecDeque<RespType>,
+    ) -> anyhow::Result<RespType> {
+        let db = self.db;
+        let propagated_args = (is_write_command(spec) && server.has_replicas()).then(|| args.clone());
+        let tracked_keys = self.tracks_reads().then(|| read_keys(spec, &args));
+        let response = self.execute(dbs, spec, args)?;
+        if let Some(keys) = tracked_keys.filter(|keys| !keys.is_empty()) {
+            if !matches!(response, RespType::SimpleError(_)) {
+                server.tracking().remember(self.id, keys);
+            }
+        }
+        if let Some(args) = propagated_args {
+            if !matches!(response, RespType::SimpleError(_)) {
+                server.propagate(db, spec.name.as_bytes(), args);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Executes the command on locked databases, returning the reply
+    fn execute(&mut self, dbs: &mut [Data], spec: &CommandSpec, mut args: VecDeque<RespType>) -> anyhow::Result<RespType> {
+        let data = &mut dbs[self.db];
+        let response = match spec.handler {
+            Handler::Ping => {
+                ensure!(args.len() <= 1, CommandError::WrongArity(spec.name.to_owned()));
+                let message = args.pop_front().map(RespType::into_bulk_string).transpose()?;
+                if !self.resp3 && self.subscriptions_count() > 0 {
+                    RespType::Array(VecDeque::from([
+                        RespType::bulk_string_from_bytes(b"pong"),
+                        RespType::BulkString(message.unwrap_or_default()),
+                    ]))
+                } else {
+                    match message {
+                        Some(message) => RespType::BulkString(message),
+                        None => RespType::SimpleString(String::from("PONG")),
+                    }
+                }
+            }
+            Handler::Auth => {
+                ensure!(args.len() <= 2, "syntax error");
+                let password = args.pop_back().unwrap().into_bulk_string()?;
+                let username = args.pop_front().map(RespType::into_bulk_string).transpose()?;
+                if username.is_none() && !self.server.requires_auth() {
+                    CommandError::err("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?").into()
+                } else {
+                    self.authenticate(username.as_deref().unwrap_or(b"default"), &password)
+                }
+            }
+            Handler::Hello => {
+                let mut protover = None;
+                let mut auth = None;
+                if let Some(arg) = args.pop_front() {
+                    protover = Some(arg.as_int().context("Protocol version is not an integer or out of range")?);
+                }
+                while let Some(arg) = args.pop_front() {
+                    match arg.as_str_bytes()? {
+                        name if name.eq_ignore_ascii_case(b"auth") => {
+                            let username = args.pop_front().context("Missing username for `auth` arg")?.into_bulk_string()?;
+                            let password = args.pop_front().context("Missing password for `auth` arg")?.into_bulk_string()?;
+                            auth = Some((username, password));
+                        }
+                        argname => bail!("Unknown parameter `{}` for `HELLO` command", String::from_utf8_lossy(argname)),
+                    }
+                }
+                let auth_error = match auth {
+                    Some((username, password)) => match self.authenticate(&username, &password) {
+                        RespType::SimpleError(err) => Some(err),
+                        _ => None,
+                    },
+                    None if !self.authenticated => Some(String::from(
+                        "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+                    )),
+                    None => None,
+                };
+                match (protover, auth_error) {
+                    (Some(version), _) if version != 2 && version != 3 => {
+                        CommandError::NoProto.into()
+                    }
+                    (_, Some(err)) => RespType::SimpleError(err),
+                    _ => {
+                        if let Some(version) = protover {
+                            self.resp3 = version == 3;
+                            self.writer.set_resp3(self.resp3);
+                            self.server.set_client_resp3(self.id, self.resp3);
+                        }
+                        let role = match self.server.replication {
+                            crate::ReplicationMode::Master => "master",
+                            crate::ReplicationMode::Slave { .. } => "replica",
+                        };
+                        RespType::Map(vec![
+                            (RespType::bulk_string_from_bytes(b"server"), RespType::bulk_string_from_bytes(b"redis")),
+                            (RespType::bulk_string_from_bytes(b"version"), RespType::bulk_string_from_bytes(b"7.2.0")),
+                            (RespType::bulk_string_from_bytes(b"proto"), RespType::Integer(if self.resp3 { 3 } else { 2 })),
+                            (RespType::bulk_string_from_bytes(b"id"), RespType::Integer(self.id as i64)),
+                            (RespType::bulk_string_from_bytes(b"mode"), RespType::bulk_string_from_bytes(b"standalone")),
+                            (RespType::bulk_string_from_bytes(b"role"), RespType::bulk_string_from_bytes(role.as_bytes())),
+                            (RespType::bulk_string_from_bytes(b"modules"), RespType::Array(VecDeque::new())),
+                        ])
+                    }
+                }
+            }
+            Handler::Publish | Handler::SPublish => {
+                let channel = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `channel` argument")?;
+                let message = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `message` argument")?;
+                let receivers = if spec.handler == Handler::SPublish {
+                    self.server.pubsub().spublish(&channel, &message)
+                } else {
+                    self.server.pubsub().publish(&channel, &message)
                 };
-                self.writer.write_item(response).await?;
+                RespType::Integer(receivers as i64)
+            }
+            Handler::PubSub => {
+                let subcommand = args.pop_front().unwrap();
+                let subcommand = subcommand.as_str_bytes()?;
+                let pubsub = self.server.pubsub();
+                match subcommand {
+                    _ if subcommand.eq_ignore_ascii_case(b"channels") || subcommand.eq_ignore_ascii_case(b"shardchannels") => {
+                        ensure!(args.len() <= 1, CommandError::WrongArity(format!("pubsub|{}", String::from_utf8_lossy(subcommand).to_ascii_lowercase())));
+                        let sharded = subcommand.eq_ignore_ascii_case(b"shardchannels");
+                        let pattern = args.pop_front().map(RespType::into_bulk_string).transpose()?;
+                        let channels = pubsub.channels(sharded, pattern.as_deref());
+                        RespType::Array(channels.into_iter().map(|name| RespType::BulkString(name.into())).collect())
+                    }
+                    _ if subcommand.eq_ignore_ascii_case(b"numsub") || subcommand.eq_ignore_ascii_case(b"shardnumsub") => {
+                        let sharded = subcommand.eq_ignore_ascii_case(b"shardnumsub");
+                        let mut response = VecDeque::with_capacity(args.len() * 2);
+                        for arg in args {
+                            let channel = arg.into_bulk_string().context("Invalid value for `channel` argument")?;
+                            let count = pubsub.numsub(sharded, &channel);
+                            response.push_back(RespType::BulkString(channel));
+                            response.push_back(RespType::Integer(count as i64));
+                        }
+                        RespType::Array(response)
+                    }
+                    _ if subcommand.eq_ignore_ascii_case(b"numpat") => {
+                        RespType::Integer(pubsub.numpat() as i64)
+                    }
+                    _ => bail!("Unknown subcommand `{}` for `PUBSUB` command", String::from_utf8_lossy(subcommand)),
+                }
             }
-            b"set" => {
-                ensure!(args.len() >= 2, "SET requires at least two args!");
+            Handler::Echo => {
+                match args.pop_front().unwrap() {
+                    arg @ RespType::BulkString(_) => arg,
+                    _ => bail!("Invalid argument for `ECHO` command"),
+                }
+            }
+            Handler::Get => {
                 let key = match args.pop_front().unwrap() {
                     RespType::BulkString(s) => s,
                     _ => bail!("Invalid value for `key` argument"),
                 };
-                let value = match args.pop_front().unwrap() {
-                    RespType::BulkString(s) => s,
-                    _ => bail!("Invalid value for `value` argument"),
-                };
+                match data.get(&key) {
+                    Some(value) => RespType::BulkString(value),
+                    None => RespType::NullBulkString,
+                }
+            }
+            Handler::Set => {
+                let key = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `key` argument")?;
+                let value = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `value` argument")?;
                 let mut expiry = None;
-                while let Some(mut arg) = args.pop_front() {
-                    let argname = arg.make_str_bytes_lowercase()?;
+                while let Some(arg) = args.pop_front() {
+                    let argname = arg.as_str_bytes()?;
                     match argname {
-                        b"px" => {
+                        _ if argname.eq_ignore_ascii_case(b"px") => {
                             let duration_mili = args.pop_front().ok_or(anyhow!("Missing value for `px` arg"))?.as_int().context("Value of `px` arg must be an integer")?;
                             ensure!(duration_mili >= 0, "Expiration cannot be negative");
                             expiry = Some(Duration::from_millis(duration_mili as u64));
@@ -95,95 +1239,721 @@ impl<'a> Connection<'a> {
                         _ => bail!("Unknown parameter `{}` for `SET` command", String::from_utf8_lossy(argname))
                     }
                 }
-                let _old_value = self.server.set(key, value, expiry).await;
-                let response = RespType::SimpleString(String::from("OK"));
-                self.writer.write_item(response).await?;
+                let _old_value = data.set(&key, value, expiry);
+                RespType::SimpleString(String::from("OK"))
             }
-            b"info" => {
-                let mut buf = Vec::new();
-                buf.extend_from_slice(b"# Replication");
-                match self.server.replication {
-                    crate::ReplicationMode::Master => buf.extend_from_slice(b"\nrole:master"),
-                    crate::ReplicationMode::Slave { .. } => buf.extend_from_slice(b"\nrole:slave"),
-                }
-                write!(&mut buf, "\nmaster_replid:{}", self.server.master_replid).context("Falied to write info data")?;
-                write!(&mut buf, "\nmaster_repl_offset:{}", self.server.master_repl_offset).context("Falied to write info data")?;
-                let response = RespType::BulkString(buf.into_boxed_slice());
-                self.writer.write_item(response).await?;
-            }
-            b"command" => {
-                eprintln!("Ignoring `COMMAND` command. Sending back empty array");
-                let response = RespType::Array(VecDeque::new());
-                self.writer.write_item(response).await?;
-            }
-            b"replconf" => {
-                eprintln!("Ignoring `REPLCONF` command.");
-                let response = RespType::SimpleString(String::from("OK"));
-                self.writer.write_item(response).await?;
+            Handler::Del | Handler::Unlink => {
+                let mut deleted = 0;
+                for arg in args {
+                    if data.del(&arg.into_bulk_string().context("Invalid value for `key` argument")?) {
+                        deleted += 1;
+                    }
+                }
+                RespType::Integer(deleted)
             }
-            b"psync" => {
-                ensure!(args.len() == 2, "PSYNC requires exactly two args!");
-                let master_id = match args.pop_front().unwrap() {
-                    RespType::BulkString(s) => s,
-                    _ => bail!("Invalid value for `key` argument"),
+            Handler::Exists | Handler::Touch => {
+                let mut count = 0;
+                for arg in args {
+                    if data.exists(&arg.into_bulk_string().context("Invalid value for `key` argument")?) {
+                        count += 1;
+                    }
+                }
+                RespType::Integer(count)
+            }
+            Handler::Type => {
+                let key = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `key` argument")?;
+                let key_type = data.key_type(&key).unwrap_or("none");
+                RespType::SimpleString(String::from(key_type))
+            }
+            Handler::Rename | Handler::RenameNx => {
+                let key = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `key` argument")?;
+                let newkey = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `newkey` argument")?;
+                let replace = spec.handler == Handler::Rename;
+                match data.rename(&key, &newkey, replace) {
+                    None => CommandError::err("no such key").into(),
+                    Some(_) if replace => RespType::SimpleString(String::from("OK")),
+                    Some(renamed) => RespType::Integer(renamed.into()),
+                }
+            }
+            Handler::Copy => {
+                let source = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `source` argument")?;
+                let destination = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `destination` argument")?;
+                let mut destination_db = Some(self.db);
+                let mut replace = false;
+                while let Some(arg) = args.pop_front() {
+                    let argname = arg.as_str_bytes()?;
+                    match argname {
+                        _ if argname.eq_ignore_ascii_case(b"db") => {
+                            let index = args.pop_front().ok_or(anyhow!("Missing value for `db` arg"))?;
+                            destination_db = db_index(&index, dbs.len())?;
+                        }
+                        _ if argname.eq_ignore_ascii_case(b"replace") => replace = true,
+                        _ => bail!("Unknown parameter `{}` for `COPY` command", String::from_utf8_lossy(argname))
+                    }
+                }
+                match destination_db {
+                    None => CommandError::err("DB index is out of range").into(),
+                    Some(db) if db == self.db && source == destination => {
+                        CommandError::err("source and destination objects are the same").into()
+                    }
+                    Some(db) => {
+                        let copied = match dbs[self.db].peek(&source) {
+                            Some(value) => dbs[db].restore(&destination, value, replace, "copy_to"),
+                            None => false,
+                        };
+                        RespType::Integer(copied.into())
+                    }
+                }
+            }
+            Handler::Move => {
+                let key = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `key` argument")?;
+                match db_index(&args.pop_front().unwrap(), dbs.len())? {
+                    None => CommandError::err("DB index is out of range").into(),
+                    Some(db) if db == self.db => {
+                        CommandError::err("source and destination objects are the same").into()
+                    }
+                    Some(db) => {
+                        let moved = !dbs[db].exists(&key)
+                            && match dbs[self.db].take(&key, "move_from") {
+                                Some(value) => dbs[db].restore(&key, value, false, "move_to"),
+                                None => false,
+                            };
+                        RespType::Integer(moved.into())
+                    }
+                }
+            }
+            Handler::Select => {
+                match db_index(&args.pop_front().unwrap(), dbs.len())? {
+                    Some(db) => {
+                        self.db = db;
+                        RespType::SimpleString(String::from("OK"))
+                    }
+                    None => CommandError::err("DB index is out of range").into(),
+                }
+            }
+            Handler::SwapDb => {
+                let first = db_index(&args.pop_front().unwrap(), dbs.len())?;
+                let second = db_index(&args.pop_front().unwrap(), dbs.len())?;
+                match first.zip(second) {
+                    Some((first, second)) => {
+                        if first != second {
+                            let (low, high) = dbs.split_at_mut(first.max(second));
+                            low[first.min(second)].swap(&mut high[0]);
+                        }
+                        RespType::SimpleString(String::from("OK"))
+                    }
+                    None => CommandError::err("DB index is out of range").into(),
+                }
+            }
+            Handler::Watch => {
+                for arg in args {
+                    let key = arg.into_bulk_string().context("Invalid value for `key` argument")?;
+                    if !self.watched.iter().any(|(db, watched, _)| *db == self.db && **watched == *key) {
+                        let version = dbs[self.db].watch(&key);
+                        self.watched.push((self.db, Box::from(&*key), version));
+                    }
+                }
+                RespType::SimpleString(String::from("OK"))
+            }
+            Handler::Unwatch => {
+                self.unwatch_all(dbs);
+                RespType::SimpleString(String::from("OK"))
+            }
+            Handler::FlushDb | Handler::FlushAll => {
+                let asynchronous = match args.pop_front() {
+                    None => false,
+                    Some(arg) => match arg.as_str_bytes()? {
+                        name if name.eq_ignore_ascii_case(b"async") => true,
+                        name if name.eq_ignore_ascii_case(b"sync") => false,
+                        argname => bail!("Unknown parameter `{}` for `FLUSHDB` command", String::from_utf8_lossy(argname)),
+                    },
                 };
-                let offset = match args.pop_front().unwrap() {
-                    RespType::BulkString(s) => s,
-                    _ => bail!("Invalid value for `value` argument"),
+                ensure!(args.is_empty(), "syntax error");
+                let flushed: Vec<_> = if spec.handler == Handler::FlushAll {
+                    dbs.iter_mut().map(Data::flush).collect()
+                } else {
+                    vec![dbs[self.db].flush()]
                 };
-                ensure!(*master_id == *b"?", "Expected unknown master ID");
-                ensure!(*offset == *b"-1", "Expected unknown master ID");
+                if asynchronous {
+                    tokio::task::spawn_blocking(move || drop(flushed));
+                }
+                RespType::SimpleString(String::from("OK"))
+            }
+            Handler::RandomKey => {
+                match data.random_key() {
+                    Some(key) => RespType::BulkString(key.into()),
+                    None => RespType::NullBulkString,
+                }
+            }
+            Handler::DbSize => {
+                RespType::Integer(data.len() as i64)
+            }
+            Handler::Keys => {
+                let pattern = args.pop_front().unwrap().into_bulk_string().context("Invalid value for `pattern` argument")?;
+                let keys = data.keys(&pattern);
+                RespType::Array(keys.into_iter().map(|name| RespType::BulkString(name.into())).collect())
+            }
+            Handler::Scan => {
+                let cursor = std::str::from_utf8(args.pop_front().unwrap().as_str_bytes()?)
+                    .ok()
+                    .and_then(|c| c.parse().ok())
+                    .context("Invalid cursor")?;
+                let mut count = 10;
+                let mut pattern = None;
+                let mut key_type = None;
+                while let Some(arg) = args.pop_front() {
+                    let argname = arg.as_str_bytes()?;
+                    match argname {
+                        _ if argname.eq_ignore_ascii_case(b"match") => {
+                            pattern = Some(args.pop_front().ok_or(anyhow!("Missing value for `match` arg"))?.into_bulk_string()?);
+                        }
+                        _ if argname.eq_ignore_ascii_case(b"count") => {
+                            count = args.pop_front().ok_or(anyhow!("Missing value for `count` arg"))?.as_int().context("Value of `count` arg must be an integer")?;
+                            ensure!(count >= 1, "Value of `count` arg must be positive");
+                        }
+                        _ if argname.eq_ignore_ascii_case(b"type") => {
+                            key_type = Some(args.pop_front().ok_or(anyhow!("Missing value for `type` arg"))?.into_bulk_string()?);
+                        }
+                        _ => bail!("Unknown parameter `{}` for `SCAN` command", String::from_utf8_lossy(argname))
+                    }
+                }
+                let (cursor, keys) = data.scan(cursor, count as usize, pattern.as_deref(), key_type.as_deref());
+                RespType::Array(VecDeque::from([
+                    RespType::bulk_string_from_string(cursor.to_string()),
+                    RespType::Array(keys.into_iter().map(|name| RespType::BulkString(name.into())).collect()),
+                ]))
+            }
+            Handler::Info => {
+                let mut sections = Vec::with_capacity(args.len());
+                for arg in &args {
+                    sections.push(arg.as_str_bytes()?);
+                }
+                let all = sections.is_empty()
+                    || sections.iter().any(|s| [&b"all"[..], b"default", b"everything"].iter().any(|all| s.eq_ignore_ascii_case(all)));
+                let wants = |name: &[u8]| all || sections.iter().any(|s| s.eq_ignore_ascii_case(name));
+
+                let mut buf = Vec::new();
+                if wants(b"clients") {
+                    info_section(&mut buf, "Clients");
+                    write!(&mut buf, "\nconnected_clients:{}", self.server.connected_clients()).context("Falied to write info data")?;
+                    write!(&mut buf, "\nclient_recent_max_output_buffer:{}", self.server.max_output_buffer()).context("Falied to write info data")?;
+                }
+                if wants(b"stats") {
+                    info_section(&mut buf, "Stats");
+                    let expired_keys: u64 = dbs.iter().map(|db| db.expired_keys).sum();
+                    write!(&mut buf, "\nexpired_keys:{}", expired_keys).context("Falied to write info data")?;
+                    write!(&mut buf, "\nexpired_stale_perc:{:.2}", self.server.expired_stale_perc() * 100.0).context("Falied to write info data")?;
+                    write!(&mut buf, "\nrejected_connections:{}", self.server.rejected_connections()).context("Falied to write info data")?;
+                    write!(&mut buf, "\ntracking_total_keys:{}", self.server.tracking().len()).context("Falied to write info data")?;
+                }
+                if wants(b"replication") {
+                    info_section(&mut buf, "Replication");
+                    match &self.server.replication {
+                        crate::ReplicationMode::Master => buf.extend_from_slice(b"\nrole:master"),
+                        crate::ReplicationMode::Slave { host, port } => {
+                            buf.extend_from_slice(b"\nrole:slave");
+                            write!(&mut buf, "\nmaster_host:{host}\nmaster_port:{port}").context("Falied to write info data")?;
+                        }
+                    }
+                    write!(&mut buf, "\nmaster_replid:{}", self.server.master_replid).context("Falied to write info data")?;
+                    write!(&mut buf, "\nmaster_repl_offset:{}", self.server.master_repl_offset()).context("Falied to write info data")?;
+                }
+                if wants(b"keyspace") {
+                    info_section(&mut buf, "Keyspace");
+                    for (index, db) in dbs.iter().enumerate().filter(|(_, db)| !db.is_empty()) {
+                        write!(&mut buf, "\ndb{}:keys={},expires={},avg_ttl={}", index, db.len(), db.expires_count(), db.avg_ttl).context("Falied to write info data")?;
+                    }
+                }
+                RespType::BulkString(buf.into())
+            }
+            Handler::Client => self.client(args)?,
+            Handler::Acl => self.acl(args)?,
+            Handler::Config => {
+                let subcommand = args.pop_front().context("Missing CONFIG subcommand")?;
+                match subcommand.as_str_bytes()? {
+                    name if name.eq_ignore_ascii_case(b"get") => {
+                        let mut patterns = Vec::with_capacity(args.len());
+                        for arg in &args {
+                            patterns.push(arg.as_str_bytes()?);
+                        }
+                        let params = self.server.config_get().into_iter().filter(|(name, _)| {
+                            patterns.iter().any(|pattern| glob_match(pattern, name.as_bytes(), true))
+                        });
+                        RespType::Map(
+                            params
+                                .map(|(name, value)| (RespType::bulk_string_from_bytes(name.as_bytes()), RespType::bulk_string_from_string(value)))
+                                .collect(),
+                        )
+                    }
+                    name if name.eq_ignore_ascii_case(b"set") => {
+                        ensure!(args.len().is_multiple_of(2), CommandError::WrongArity(String::from("config|set")));
+                        let mut result = RespType::SimpleString(String::from("OK"));
+                        while let (Some(name), Some(value)) = (args.pop_front(), args.pop_front()) {
+                            let name = String::from_utf8_lossy(name.as_str_bytes()?).to_ascii_lowercase();
+                            let value = String::from_utf8_lossy(value.as_str_bytes()?).into_owned();
+                            if let Err(err) = self.server.config_set(&name, &value) {
+                                result = CommandError::err(format!("CONFIG SET failed (possibly related to argument '{name}') - {err}")).into();
+                                break;
+                            }
+                        }
+                        result
+                    }
+                    _ => bail!("Unknown subcommand `{}` for `CONFIG` command", String::from_utf8_lossy(subcommand.as_str_bytes()?)),
+                }
+            }
+            Handler::Command => command_introspection(args)?,
+            Handler::ReplConf => {
+                eprintln!("Ignoring `REPLCONF` command.");
+                RespType::SimpleString(String::from("OK"))
+            }
+            _ => bail!("Unknown command `{}`", spec.name),
+        };
+        Ok(response)
+    }
 
-                let response = RespType::SimpleString(format!("FULLRESYNC {} {}", self.server.master_replid, self.server.master_repl_offset));
-                self.writer.write_item(response).await?;
+    async fn psync(&mut self, mut args: VecDeque<RespType>) -> anyhow::Result<()> {
+        let master_id = match args.pop_front().unwrap() {
+            RespType::BulkString(s) => s,
+            _ => bail!("Invalid value for `key` argument"),
+        };
+        let offset = match args.pop_front().unwrap() {
+            RespType::BulkString(s) => s,
+            _ => bail!("Invalid value for `value` argument"),
+        };
+        ensure!(*master_id == *b"?", "Expected unknown master ID");
+        ensure!(*offset == *b"-1", "Expected unknown master ID");
 
-                self.writer.write_rdb_file(EMPTY_RDB_FILE).await?;
+        // Register before sending the snapshot, so no write after it is missed
+        self.output.set_class(OutputClass::Replica);
+        self.replication_stream = Some(self.server.add_replica(&self.output));
+        let response = RespType::SimpleString(format!("FULLRESYNC {} {}", self.server.master_replid, self.server.master_repl_offset()));
+        self.writer.write_item(response).await?;
+
+        self.writer.write_rdb_file(EMPTY_RDB_FILE).await?;
+        Ok(())
+    }
+
+    /// Writes the output buffer to the client
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().await?;
+        self.output.set_buffered(0);
+        Ok(())
+    }
+
+    async fn reply(&mut self, response: RespType) -> anyhow::Result<()> {
+        // Master doesn't expect any replies to the commands it propagates
+        if self.kind != ClientKind::Master {
+            self.writer.buffer_item(response);
+            self.output.set_buffered(self.writer.buffered_len());
+            // Long pipelines are written out in chunks instead of buffering all their replies
+            if self.writer.buffered_len() >= OUTPUT_FLUSH_THRESHOLD {
+                self.flush().await?;
             }
-            _ => bail!("Unknown command `{}`", String::from_utf8_lossy(command)),
         }
         Ok(())
     }
 
-    pub async fn run_processing_loop(mut self) -> anyhow::Result<()> {
-        eprintln!("Starting processing loop for client: {:?}", self.addr);
+    /// Forwards write commands propagated by the server to the replica connected through this connection
+    async fn feed_replica(&mut self, mut stream: OutputReceiver<Arc<[u8]>>) -> anyhow::Result<()> {
+        eprintln!("Streaming replication to replica: {:?}", self.addr);
+        self.kind = ClientKind::Replica;
         loop {
-            let Some(item) = self.reader.read_item().await? else {
-                eprintln!("Terminating processing loop for client: {:?}", self.addr);
-                break;
-            };
-            match item {
-                RespType::SimpleString(mut s) => {
-                    s.make_ascii_lowercase();
-                    self.command(s.as_bytes(), VecDeque::new()).await?
-                }
-                RespType::SimpleError(err) => bail!("{err}"),
-                RespType::Integer(_) => bail!("Unexpected integer received"),
-                RespType::BulkString(mut s) => {
-                    s.make_ascii_lowercase();
-                    self.command(&s, VecDeque::new()).await?
-                }
-                RespType::Array(mut items) => {
-                    let command = items
-                        .pop_front()
-                        .ok_or(anyhow!("Expected command, got empty array"))?;
-                    match command {
-                        RespType::SimpleString(mut s) => {
-                            s.make_ascii_lowercase();
-                            self.command(s.as_bytes(), items).await?
+            let mut read = std::pin::pin!(self.reader.read_item());
+            loop {
+                tokio::select! {
+                    item = &mut read => match item? {
+                        // Replica only sends `REPLCONF ACK <offset>`, which we don't track
+                        Some(_) => break,
+                        None => {
+                            eprintln!("Replica disconnected: {:?}", self.addr);
+                            return Ok(());
                         }
-                        RespType::SimpleError(err) => bail!("{err}"),
-                        RespType::Integer(_) => bail!("Unexpected integer received"),
-                        RespType::BulkString(mut s) => {
-                            s.make_ascii_lowercase();
-                            self.command(&s, items).await?
+                    },
+                    data = stream.recv() => match data {
+                        Some(data) => self.writer.write_raw(&data).await?,
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+    }
+
+    pub async fn run_processing_loop(mut self) -> anyhow::Result<()> {
+        let output = Arc::clone(&self.output);
+        // Closing interrupts the connection even while it's blocked writing to a slow client
+        let result = tokio::select! {
+            result = self.processing_loop() => result,
+            () = output.closed() => Ok(()),
+        };
+        self.cleanup().await;
+        result
+    }
+
+    /// Releases server-side state held for the connection once it's closed
+    async fn cleanup(&mut self) {
+        self.unsubscribe_all();
+        if self.tracking.take().is_some() {
+            self.server.tracking().disable(self.id);
+        }
+        self.server.unregister_client(self.id);
+        if !self.watched.is_empty() {
+            self.unwatch_all(&mut self.server.clone().data().await);
+        }
+    }
+
+    async fn processing_loop(&mut self) -> anyhow::Result<()> {
+        eprintln!("Starting processing loop for client: {:?}", self.addr);
+        while !self.quit {
+            let item = match self.reader.try_read_item() {
+                // Replies are written once all the pipelined commands received so far are processed
+                Ok(None) => {
+                    self.flush().await?;
+                    // Subscribers wait for messages, not idle, and our master may have nothing to replicate
+                    let idle_exempt = self.kind != ClientKind::Normal || self.subscriptions_count() > 0;
+                    // Keep the read going while delivering pushes, so no partially read data is lost
+                    let mut read = std::pin::pin!(self.reader.read_item());
+                    loop {
+                        tokio::select! {
+                            item = &mut read => break item,
+                            Some(push) = self.push_rx.recv() => {
+                                // Pushes queued meanwhile go out in the same write
+                                self.writer.buffer_item(push);
+                                while let Some(push) = self.push_rx.try_recv() {
+                                    self.writer.buffer_item(push);
+                                }
+                                self.output.set_buffered(self.writer.buffered_len());
+                                self.writer.flush().await?;
+                                self.output.set_buffered(0);
+                            }
+                            () = idle_timeout(&self.server, self.last_interaction), if !idle_exempt => {
+                                eprintln!("Closing idle client: {:?}", self.addr);
+                                return Ok(());
+                            }
                         }
-                        RespType::Array(_) => bail!("Unexpected nested array"),
-                        RespType::NullBulkString => unreachable!("Reader doesn't parse this value"),
                     }
                 }
-                RespType::NullBulkString => unreachable!("Reader doesn't parse this value"),
+                item => item,
             };
+            let request = item.and_then(|item| Ok(item.map(split_command).transpose()?));
+            let (command, args) = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => {
+                    eprintln!("Terminating processing loop for client: {:?}", self.addr);
+                    break;
+                }
+                Err(err) => {
+                    // Malformed request is replied before closing the connection
+                    if let Some(protocol_error) = err.downcast_ref::<ProtocolError>() {
+                        self.reply(RespType::SimpleError(protocol_error.to_string())).await?;
+                        self.flush().await?;
+                    }
+                    return Err(err);
+                }
+            };
+            self.command(&command, args).await?;
+            // Pushes caused by the command, e.g. invalidations of keys it modified, follow its reply
+            while let Some(push) = self.push_rx.try_recv() {
+                self.writer.buffer_item(push);
+            }
+            self.output.set_buffered(self.writer.buffered_len());
+            if let Some(stream) = self.replication_stream.take() {
+                return self.feed_replica(stream).await;
+            }
         }
-        Ok(())
+        // Reply to `QUIT`
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::commands;
+    use crate::resp::RespType;
+    use crate::testing::{array, bulk, int, ok, server, simple, TestClient};
+
+    #[tokio::test]
+    async fn pubsub_introspection_separates_shard_channels() {
+        let server = server(|_| {});
+        let mut subscriber = TestClient::connect(&server);
+        subscriber.cmd(&["SUBSCRIBE", "news"]).await;
+        let mut shard_subscriber = TestClient::connect(&server);
+        shard_subscriber.cmd(&["SSUBSCRIBE", "orders"]).await;
+
+        let mut client = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["PUBSUB", "CHANNELS"]).await, array([bulk("news")]));
+        assert_eq!(client.cmd(&["PUBSUB", "SHARDCHANNELS"]).await, array([bulk("orders")]));
+        assert_eq!(client.cmd(&["pubsub", "shardchannels", "ord*"]).await, array([bulk("orders")]));
+        assert_eq!(
+            client.cmd(&["PUBSUB", "NUMSUB", "news", "orders"]).await,
+            array([bulk("news"), int(1), bulk("orders"), int(0)])
+        );
+        assert_eq!(
+            client.cmd(&["PUBSUB", "SHARDNUMSUB", "news", "orders"]).await,
+            array([bulk("news"), int(0), bulk("orders"), int(1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn client_pause_write_delays_only_writes() {
+        let server = server(|_| {});
+        let mut admin = TestClient::connect(&server);
+        let mut writer = TestClient::connect(&server);
+        let mut reader = TestClient::connect(&server);
+        assert_eq!(admin.cmd(&["CLIENT", "PAUSE", "10000", "WRITE"]).await, ok());
+
+        writer.send(&["SET", "key", "value"]).await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), writer.read()).await.is_err());
+        assert_eq!(reader.cmd(&["GET", "key"]).await, RespType::NullBulkString);
+
+        assert_eq!(admin.cmd(&["CLIENT", "UNPAUSE"]).await, ok());
+        assert_eq!(writer.read().await, Some(ok()));
+        assert_eq!(reader.cmd(&["GET", "key"]).await, bulk("value"));
+    }
+
+    #[tokio::test]
+    async fn client_unpause_runs_while_all_clients_are_paused() {
+        let server = server(|_| {});
+        let mut admin = TestClient::connect(&server);
+        let mut client = TestClient::connect(&server);
+        assert_eq!(admin.cmd(&["CLIENT", "PAUSE", "10000"]).await, ok());
+        client.send(&["PING"]).await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), client.read()).await.is_err());
+        assert_eq!(admin.cmd(&["client", "unpause"]).await, ok());
+        assert_eq!(client.read().await, Some(simple("PONG")));
+    }
+
+    #[tokio::test]
+    async fn client_pause_rejects_invalid_timeouts() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        assert_eq!(client.err(&["CLIENT", "PAUSE", "-1"]).await, "ERR timeout is negative");
+        assert_eq!(client.err(&["CLIENT", "PAUSE", &i64::MAX.to_string()]).await, "ERR timeout is out of range");
+        assert_eq!(client.err(&["CLIENT", "PAUSE", "100", "NONE"]).await, "ERR syntax error");
+        assert_eq!(client.cmd(&["SET", "key", "value"]).await, ok());
+    }
+
+    #[tokio::test]
+    async fn client_list_and_kill() {
+        let server = server(|_| {});
+        let mut admin = TestClient::connect(&server);
+        let mut victim = TestClient::connect(&server);
+        assert_eq!(victim.cmd(&["CLIENT", "SETNAME", "victim"]).await, ok());
+
+        let list = match admin.cmd(&["CLIENT", "LIST"]).await {
+            RespType::BulkString(list) => String::from_utf8_lossy(&list).into_owned(),
+            reply => panic!("CLIENT LIST must reply with a bulk string, got {reply:?}"),
+        };
+        let lines: Vec<_> = list.lines().collect();
+        assert_eq!(lines.len(), 2, "{list}");
+        assert!(lines.iter().any(|line| line.contains(&format!("addr={} ", victim.addr)) && line.contains(" name=victim ")), "{list}");
+        assert!(lines.iter().any(|line| line.contains(&format!("addr={} ", admin.addr)) && line.contains(" cmd=client|list ")), "{list}");
+
+        assert_eq!(admin.cmd(&["CLIENT", "KILL", "ADDR", &victim.addr.to_string()]).await, int(1));
+        assert!(victim.is_closed().await);
+        assert_eq!(admin.err(&["CLIENT", "KILL", &victim.addr.to_string()]).await, "ERR No such client");
+        assert_eq!(admin.cmd(&["CLIENT", "KILL", "ADDR", &admin.addr.to_string(), "SKIPME", "yes"]).await, int(0));
+    }
+
+    #[tokio::test]
+    async fn acl_deluser_disconnects_clients_of_user() {
+        let server = server(|_| {});
+        let mut admin = TestClient::connect(&server);
+        assert_eq!(admin.cmd(&["ACL", "SETUSER", "bob", "on", ">pw", "+@all", "~*"]).await, ok());
+        let mut bob = TestClient::connect(&server);
+        assert_eq!(bob.cmd(&["AUTH", "bob", "pw"]).await, ok());
+        let mut other = TestClient::connect(&server);
+
+        assert_eq!(admin.cmd(&["ACL", "DELUSER", "bob", "nobody"]).await, int(1));
+        assert!(bob.is_closed().await);
+        assert_eq!(other.cmd(&["ACL", "WHOAMI"]).await, bulk("default"));
+
+        // The caller gets the reply before it's disconnected
+        assert_eq!(admin.cmd(&["ACL", "SETUSER", "carol", "on", ">pw", "+@all"]).await, ok());
+        assert_eq!(admin.cmd(&["AUTH", "carol", "pw"]).await, ok());
+        assert_eq!(admin.cmd(&["ACL", "DELUSER", "carol"]).await, int(1));
+        assert!(admin.is_closed().await);
+    }
+
+    #[tokio::test]
+    async fn reset_authenticates_as_default_user() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["ACL", "SETUSER", "bob", "on", ">pw", "+@all"]).await, ok());
+        assert_eq!(client.cmd(&["AUTH", "bob", "pw"]).await, ok());
+        assert_eq!(client.cmd(&["RESET"]).await, simple("RESET"));
+        assert_eq!(client.cmd(&["ACL", "WHOAMI"]).await, bulk("default"));
+
+        // Once `default` requires a password, the connection is left unauthenticated
+        assert_eq!(client.cmd(&["ACL", "SETUSER", "default", ">secret"]).await, ok());
+        assert_eq!(client.cmd(&["RESET"]).await, simple("RESET"));
+        assert!(client.err(&["ACL", "WHOAMI"]).await.starts_with("NOAUTH"));
+        assert_eq!(client.cmd(&["AUTH", "secret"]).await, ok());
+        assert_eq!(client.cmd(&["ACL", "WHOAMI"]).await, bulk("default"));
+    }
+
+    #[tokio::test]
+    async fn multi_exec_runs_queued_commands() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        let mut other = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "1"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["GET", "key"]).await, simple("QUEUED"));
+        // Nothing runs before `EXEC`
+        assert_eq!(other.cmd(&["GET", "key"]).await, RespType::NullBulkString);
+        assert_eq!(client.cmd(&["EXEC"]).await, array([ok(), bulk("1")]));
+        assert_eq!(other.cmd(&["GET", "key"]).await, bulk("1"));
+
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.err(&["MULTI"]).await, "ERR MULTI calls can not be nested");
+        assert_eq!(client.cmd(&["SET", "key", "2"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["DISCARD"]).await, ok());
+        assert_eq!(client.cmd(&["GET", "key"]).await, bulk("1"));
+        assert_eq!(client.err(&["EXEC"]).await, "ERR EXEC without MULTI");
+        assert_eq!(client.err(&["DISCARD"]).await, "ERR DISCARD without MULTI");
+    }
+
+    #[tokio::test]
+    async fn multi_is_aborted_by_invalid_command() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "1"]).await, simple("QUEUED"));
+        assert!(client.err(&["SET", "key"]).await.starts_with("ERR wrong number of arguments"));
+        assert!(client.err(&["NOSUCHCOMMAND"]).await.starts_with("ERR unknown command"));
+        assert_eq!(client.err(&["EXEC"]).await, "EXECABORT Transaction discarded because of previous errors.");
+        assert_eq!(client.cmd(&["GET", "key"]).await, RespType::NullBulkString);
+
+        // Errors of queued commands at runtime don't stop the others
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "1", "PX", "-5"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["SET", "key", "2"]).await, simple("QUEUED"));
+        match client.cmd(&["EXEC"]).await {
+            RespType::Array(replies) => assert!(matches!(&replies.as_slices().0, [RespType::SimpleError(_), reply] if *reply == ok())),
+            reply => panic!("EXEC must reply with an array, got {reply:?}"),
+        }
+        assert_eq!(client.cmd(&["GET", "key"]).await, bulk("2"));
+    }
+
+    #[tokio::test]
+    async fn watch_aborts_exec_when_key_changes() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        let mut other = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["WATCH", "key"]).await, ok());
+        assert_eq!(other.cmd(&["SET", "key", "other"]).await, ok());
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "mine"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["EXEC"]).await, RespType::NullArray);
+        assert_eq!(client.cmd(&["GET", "key"]).await, bulk("other"));
+
+        // `EXEC` unwatched the key, so the change doesn't matter anymore
+        assert_eq!(other.cmd(&["SET", "key", "again"]).await, ok());
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "mine"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["EXEC"]).await, array([ok()]));
+
+        assert_eq!(client.cmd(&["WATCH", "key"]).await, ok());
+        assert_eq!(client.cmd(&["UNWATCH"]).await, ok());
+        assert_eq!(other.cmd(&["DEL", "key"]).await, int(1));
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["GET", "key"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["EXEC"]).await, array([RespType::NullBulkString]));
+
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.err(&["WATCH", "key"]).await, "ERR WATCH inside MULTI is not allowed");
+        assert!(client.err(&["EXEC"]).await.starts_with("EXECABORT"));
+    }
+
+    #[tokio::test]
+    async fn watch_aborts_exec_when_key_expires() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        let mut other = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["SET", "key", "value", "PX", "20"]).await, ok());
+        assert_eq!(client.cmd(&["WATCH", "key"]).await, ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(other.cmd(&["GET", "key"]).await, RespType::NullBulkString);
+        assert_eq!(client.cmd(&["MULTI"]).await, ok());
+        assert_eq!(client.cmd(&["SET", "key", "mine"]).await, simple("QUEUED"));
+        assert_eq!(client.cmd(&["EXEC"]).await, RespType::NullArray);
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_channel_and_pattern_subscribers() {
+        let server = server(|_| {});
+        let mut subscriber = TestClient::connect(&server);
+        assert_eq!(subscriber.cmd(&["SUBSCRIBE", "news", "sports"]).await, array([bulk("subscribe"), bulk("news"), int(1)]));
+        assert_eq!(subscriber.read().await, Some(array([bulk("subscribe"), bulk("sports"), int(2)])));
+        assert_eq!(subscriber.cmd(&["PSUBSCRIBE", "n*"]).await, array([bulk("psubscribe"), bulk("n*"), int(3)]));
+        let error = subscriber.err(&["GET", "key"]).await;
+        assert!(error.starts_with("ERR Can't execute 'get'"), "{error}");
+        assert_eq!(subscriber.cmd(&["PING"]).await, array([bulk("pong"), bulk("")]));
+
+        let mut publisher = TestClient::connect(&server);
+        assert_eq!(publisher.cmd(&["PUBSUB", "NUMPAT"]).await, int(1));
+        assert_eq!(publisher.cmd(&["PUBLISH", "news", "hello"]).await, int(2));
+        assert_eq!(subscriber.read().await, Some(array([bulk("message"), bulk("news"), bulk("hello")])));
+        assert_eq!(subscriber.read().await, Some(array([bulk("pmessage"), bulk("n*"), bulk("news"), bulk("hello")])));
+        assert_eq!(publisher.cmd(&["PUBLISH", "weather", "rain"]).await, int(0));
+
+        assert_eq!(subscriber.cmd(&["UNSUBSCRIBE", "news"]).await, array([bulk("unsubscribe"), bulk("news"), int(2)]));
+        assert_eq!(publisher.cmd(&["PUBLISH", "sports", "goal"]).await, int(1));
+        assert_eq!(subscriber.read().await, Some(array([bulk("message"), bulk("sports"), bulk("goal")])));
+        assert_eq!(subscriber.cmd(&["PUNSUBSCRIBE"]).await, array([bulk("punsubscribe"), bulk("n*"), int(1)]));
+        assert_eq!(subscriber.cmd(&["UNSUBSCRIBE"]).await, array([bulk("unsubscribe"), bulk("sports"), int(0)]));
+        // Out of subscriber mode, any command runs again
+        assert_eq!(subscriber.cmd(&["GET", "key"]).await, RespType::NullBulkString);
+        assert_eq!(publisher.cmd(&["PUBSUB", "NUMPAT"]).await, int(0));
+    }
+
+    #[tokio::test]
+    async fn commands_are_checked_against_table() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        assert_eq!(client.err(&["GET"]).await, "ERR wrong number of arguments for 'get' command");
+        assert_eq!(client.err(&["CONFIG", "GET"]).await, "ERR wrong number of arguments for 'config|get' command");
+        assert_eq!(client.err(&["NOSUCH", "a"]).await, "ERR unknown command 'NOSUCH', with args beginning with: 'a' ");
+        assert_eq!(client.err(&["CLIENT", "NOSUCH"]).await, "ERR unknown subcommand 'NOSUCH'. Try CLIENT HELP.");
+        assert_eq!(client.cmd(&["COMMAND", "COUNT"]).await, int(commands::COMMANDS.len() as i64));
+        assert_eq!(client.cmd(&["COMMAND", "GETKEYS", "DEL", "a", "b"]).await, array([bulk("a"), bulk("b")]));
+        assert_eq!(client.cmd(&["command", "list", "filterby", "pattern", "flush*"]).await, array([bulk("flushdb"), bulk("flushall")]));
+    }
+
+    #[tokio::test]
+    async fn pipelined_replies_are_written_together() {
+        let server = server(|_| {});
+        let (mut client, writes) = TestClient::connect_counting(&server);
+        let pings = [&["PING"][..]; 100];
+        client.send_pipeline(&pings).await;
+        for _ in pings {
+            assert_eq!(client.read().await, Some(simple("PONG")));
+        }
+        let writes = writes.load(Ordering::Relaxed);
+        assert!(writes < 5, "{writes} writes for 100 replies");
+    }
+
+    #[tokio::test]
+    async fn pushes_queued_meanwhile_are_written_together() {
+        let server = server(|_| {});
+        let (mut subscriber, writes) = TestClient::connect_counting(&server);
+        subscriber.cmd(&["SUBSCRIBE", "news"]).await;
+        let before = writes.load(Ordering::Relaxed);
+
+        // Messages published by a transaction are all queued before the subscriber gets to run
+        let mut publisher = TestClient::connect(&server);
+        publisher.cmd(&["MULTI"]).await;
+        for i in 0..100 {
+            publisher.cmd(&["PUBLISH", "news", &i.to_string()]).await;
+        }
+        publisher.cmd(&["EXEC"]).await;
+        for i in 0..100 {
+            assert_eq!(subscriber.read().await, Some(array([bulk("message"), bulk("news"), bulk(&i.to_string())])));
+        }
+        let writes = writes.load(Ordering::Relaxed) - before;
+        assert!(writes < 5, "{writes} writes for 100 messages");
     }
 }
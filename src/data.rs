@@ -1,44 +1,555 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+
+use crate::glob::glob_match;
+use crate::notify::{self, Notifier};
+use crate::tracking::Tracking;
+
+/// Number of keys with expiry sampled in single iteration of active expire cycle
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+/// Percentage of expired keys among sampled ones under which the cycle stops early
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE: usize = 10;
+/// Values at least this long are stored as sliced out of the request, shorter ones are copied so they don't
+/// keep the whole read buffer of the connection allocated
+const SHARED_VALUE_MIN_LEN: usize = 16 * 1024;
+
+#[derive(Debug)]
+struct Expiry {
+    at: Instant,
+    /// Position of the key in `Data::expires`
+    idx: usize,
+}
+
 #[derive(Debug)]
 struct ValueWithMeta {
-    value: Box<[u8]>,
+    value: Bytes,
+    expiry: Option<Expiry>,
+    /// Position of the key in `Data::keys`
+    idx: usize,
+}
+
+/// Value with its expiry detached from the keyspace, used to move keys between databases
+#[derive(Debug)]
+pub struct StoredValue {
+    value: Bytes,
     expiry: Option<Instant>,
 }
 
+/// Modification version of a key watched by at least one connection
+#[derive(Debug)]
+struct WatchedKey {
+    watchers: usize,
+    version: u64,
+}
+
 #[derive(Debug, Default)]
+pub struct ExpireCycleResult {
+    pub sampled: usize,
+    pub expired: usize,
+    pub timed_out: bool,
+}
+
+#[derive(Debug)]
 pub struct Data {
+    /// Index of the database, used in keyspace notifications
+    id: usize,
+    notifier: Arc<Notifier>,
+    /// Invalidation table of client-side caching, notified about every modified key
+    tracking: Arc<Mutex<Tracking>>,
     data: HashMap<Box<[u8]>, ValueWithMeta>,
+    /// All keys, so `RANDOMKEY` can pick one in constant time
+    keys: Vec<Box<[u8]>>,
+    /// All keys ordered by their scan hash, so `SCAN` continues from the cursor without visiting the others
+    scan_order: BTreeSet<(u64, Box<[u8]>)>,
+    /// Keys with expiry set, so the active expire cycle can sample them randomly
+    expires: Vec<Box<[u8]>>,
+    /// Keys watched by `WATCH`, their version is bumped on every modification
+    watched: HashMap<Box<[u8]>, WatchedKey>,
+    rng_state: u64,
+    pub(crate) expired_keys: u64,
+    /// Running estimate of TTL of keys with expiry (in milliseconds), updated by the active expire cycle
+    pub(crate) avg_ttl: u64,
 }
 
-impl ValueWithMeta {
-    fn new(value: Box<[u8]>, expiry: Option<Duration>) -> Self {
+impl Data {
+    pub fn new(id: usize, notifier: Arc<Notifier>, tracking: Arc<Mutex<Tracking>>) -> Self {
+        let seed = RandomState::new().build_hasher().finish();
         Self {
-            value,
-            expiry: expiry.map(|d| Instant::now().checked_add(d).expect("Invalid duration for expire")),
+            id,
+            notifier,
+            tracking,
+            data: HashMap::new(),
+            keys: Vec::new(),
+            scan_order: BTreeSet::new(),
+            expires: Vec::new(),
+            watched: HashMap::new(),
+            // xorshift state must never be zero
+            rng_state: seed | 1,
+            expired_keys: 0,
+            avg_ttl: 0,
         }
     }
 }
 
+impl ValueWithMeta {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expiry, Some(Expiry { at, .. }) if at <= now)
+    }
+
+    fn expiry_at(&self) -> Option<Instant> {
+        self.expiry.as_ref().map(|e| e.at)
+    }
+
+    fn type_name(&self) -> &'static str {
+        "string"
+    }
+}
+
+/// Hash used to order keys for `SCAN`.
+///
+/// Unlike the `HashMap` bucket order, it doesn't change when the map is resized, so the cursor
+/// (lower bound of hashes not yet returned) stays valid for the whole iteration.
+fn scan_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl Data {
-    pub async fn get(&mut self, key: Box<[u8]>) -> Option<Box<[u8]>> {
-        let entry = match self.data.entry(key) {
-            Entry::Occupied(e) => e,
-            Entry::Vacant(_) => return None,
-        };
-        let val_meta = entry.get();
-        if let Some(exp) = val_meta.expiry {
-            if exp <= Instant::now() {
-                entry.remove();
-                return None
-            }
-        };
-        Some(val_meta.value.clone())
-    }
-    pub async fn set(&mut self, key: Box<[u8]>, value: Box<[u8]>, expiry: Option<Duration>) -> Option<Box<[u8]>> {
-        let value_with_meta = ValueWithMeta::new(value, expiry);
-        self.data.insert(key, value_with_meta).map(|v| v.value)
+    /// xorshift64* - good enough for sampling keys
+    fn random_index(&mut self, len: usize) -> usize {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        (self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d) % len as u64) as usize
+    }
+
+    /// Marks the key as modified for connections watching or caching it
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+        self.tracking.lock().unwrap().key_modified(key);
+    }
+
+    /// Marks watched keys as modified if they are present in this or the `other` keyspace, i.e. the ones
+    /// a flush or swap between the two changed
+    fn touch_watched_in(&mut self, other: &Data) {
+        for (key, watched) in &mut self.watched {
+            if self.data.contains_key(key) || other.data.contains_key(key) {
+                watched.version += 1;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: Bytes, expiry: Option<Instant>) -> Option<ValueWithMeta> {
+        let old = self.remove(key);
+        self.touch(key);
+        let key: Box<[u8]> = key.into();
+        let expiry = expiry.map(|at| {
+            self.expires.push(key.clone());
+            Expiry { at, idx: self.expires.len() - 1 }
+        });
+        self.keys.push(key.clone());
+        self.scan_order.insert((scan_hash(&key), key.clone()));
+        let idx = self.keys.len() - 1;
+        self.data.insert(key, ValueWithMeta { value, expiry, idx });
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<ValueWithMeta> {
+        let removed = self.data.remove(key)?;
+        self.touch(key);
+        self.keys.swap_remove(removed.idx);
+        if let Some(moved) = self.keys.get(removed.idx) {
+            self.data.get_mut(moved).expect("Key in keys must be present in data").idx = removed.idx;
+        }
+        self.scan_order.remove(&(scan_hash(key), key.into()));
+        if let Some(Expiry { idx, .. }) = removed.expiry {
+            self.expires.swap_remove(idx);
+            if let Some(moved) = self.expires.get(idx) {
+                let moved = self.data.get_mut(moved).expect("Key in expires must be present in data");
+                moved.expiry.as_mut().expect("Key in expires must have expiry").idx = idx;
+            }
+        }
+        Some(removed)
+    }
+
+    fn notify(&self, class: u32, event: &str, key: &[u8]) {
+        self.notifier.notify(class, event, key, self.id);
+    }
+
+    /// Removes already expired key
+    fn expire(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.expired_keys += 1;
+        self.notify(notify::EXPIRED, "expired", key);
+    }
+
+    /// Looks up the key, removing it first if it's already expired
+    fn lookup(&mut self, key: &[u8]) -> Option<&ValueWithMeta> {
+        if self.data.get(key)?.is_expired(Instant::now()) {
+            self.expire(key);
+            return None;
+        }
+        self.data.get(key)
+    }
+
+    /// Stores the value, notifying about creation of a new key
+    fn store(&mut self, key: &[u8], value: Bytes, expiry: Option<Instant>) -> Option<ValueWithMeta> {
+        let is_new = self.lookup(key).is_none();
+        let old = self.insert(key, value, expiry);
+        if is_new {
+            self.notify(notify::NEW, "new", key);
+        }
+        old
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Bytes> {
+        let value = self.lookup(key).map(|v| v.value.clone());
+        if value.is_none() {
+            self.notify(notify::KEY_MISS, "keymiss", key);
+        }
+        value
+    }
+    pub fn set(&mut self, key: &[u8], value: Bytes, expiry: Option<Duration>) -> Option<Bytes> {
+        let expiry = expiry.map(|d| Instant::now().checked_add(d).expect("Invalid duration for expire"));
+        let value = if value.len() >= SHARED_VALUE_MIN_LEN { value } else { Bytes::copy_from_slice(&value) };
+        let old = self.store(key, value, expiry);
+        self.notify(notify::STRING, "set", key);
+        if expiry.is_some() {
+            self.notify(notify::GENERIC, "expire", key);
+        }
+        old.map(|v| v.value)
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> bool {
+        let deleted = self.lookup(key).is_some() && self.remove(key).is_some();
+        if deleted {
+            self.notify(notify::GENERIC, "del", key);
+        }
+        deleted
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    pub fn key_type(&mut self, key: &[u8]) -> Option<&'static str> {
+        self.lookup(key).map(ValueWithMeta::type_name)
+    }
+
+    /// Renames `from` to `to` keeping its expiry.
+    ///
+    /// Returns `None` if `from` doesn't exist and `Some(false)` if `to` exists and `replace` is not set.
+    pub fn rename(&mut self, from: &[u8], to: &[u8], replace: bool) -> Option<bool> {
+        self.lookup(from)?;
+        if from == to {
+            return Some(replace);
+        }
+        if !replace && self.lookup(to).is_some() {
+            return Some(false);
+        }
+        let value = self.remove(from).expect("Key was looked up");
+        self.notify(notify::GENERIC, "rename_from", from);
+        let expiry = value.expiry_at();
+        self.store(to, value.value, expiry);
+        self.notify(notify::GENERIC, "rename_to", to);
+        Some(true)
+    }
+
+    /// Returns copy of the value including its expiry
+    pub fn peek(&mut self, key: &[u8]) -> Option<StoredValue> {
+        self.lookup(key).map(|v| StoredValue {
+            value: v.value.clone(),
+            expiry: v.expiry_at(),
+        })
+    }
+
+    /// Removes the key returning its value including expiry, `event` is the generic keyspace event
+    pub fn take(&mut self, key: &[u8], event: &str) -> Option<StoredValue> {
+        self.lookup(key)?;
+        let value = self.remove(key).map(|v| StoredValue {
+            expiry: v.expiry_at(),
+            value: v.value,
+        });
+        self.notify(notify::GENERIC, event, key);
+        value
+    }
+
+    /// Stores value previously obtained by `peek` or `take`, `event` is the generic keyspace event.
+    ///
+    /// Returns `false` if the key exists and `replace` is not set.
+    pub fn restore(&mut self, key: &[u8], value: StoredValue, replace: bool, event: &str) -> bool {
+        if !replace && self.lookup(key).is_some() {
+            return false;
+        }
+        self.store(key, value.value, value.expiry);
+        self.notify(notify::GENERIC, event, key);
+        true
+    }
+
+    /// Removes all keys, returning them so they may be dropped elsewhere
+    pub fn flush(&mut self) -> Data {
+        let empty = Data::new(self.id, Arc::clone(&self.notifier), Arc::clone(&self.tracking));
+        let mut flushed = std::mem::replace(self, empty);
+        self.tracking.lock().unwrap().flushed();
+        self.expired_keys = std::mem::take(&mut flushed.expired_keys);
+        self.watched = std::mem::take(&mut flushed.watched);
+        self.touch_watched_in(&flushed);
+        flushed
+    }
+
+    /// Swaps keys of two databases, the watched keys stay with their database index
+    pub fn swap(&mut self, other: &mut Data) {
+        std::mem::swap(self, other);
+        std::mem::swap(&mut self.id, &mut other.id);
+        std::mem::swap(&mut self.watched, &mut other.watched);
+        self.touch_watched_in(other);
+        other.touch_watched_in(self);
+    }
+
+    /// Starts watching the key, returning its current version
+    pub fn watch(&mut self, key: &[u8]) -> u64 {
+        // Expire the key now, so it's not reported as modified later
+        self.lookup(key);
+        let watched = self
+            .watched
+            .entry(key.into())
+            .or_insert(WatchedKey { watchers: 0, version: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Checks whether the watched key was modified (or has expired) since `WATCH` returned `version`
+    pub fn is_modified_since(&mut self, key: &[u8], version: u64) -> bool {
+        self.lookup(key);
+        self.watched.get(key).map(|w| w.version) != Some(version)
+    }
+
+    pub fn random_key(&mut self) -> Option<Box<[u8]>> {
+        // Expired keys are removed when hit, so give up eventually if there are too many of them
+        for _ in 0..100 {
+            if self.data.is_empty() {
+                return None;
+            }
+            let idx = self.random_index(self.keys.len());
+            let key = self.keys[idx].clone();
+            if self.lookup(&key).is_some() {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn expires_count(&self) -> usize {
+        self.expires.len()
+    }
+
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Box<[u8]>> {
+        let now = Instant::now();
+        self.data
+            .iter()
+            .filter(|(key, value)| !value.is_expired(now) && glob_match(pattern, key, false))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Returns next cursor and keys with scan hash between `cursor` and the next cursor.
+    ///
+    /// Roughly `count` keys are visited, before filtering them by `pattern` and `key_type`.
+    /// Cursor `0` starts a new iteration and is returned once the iteration is complete.
+    pub fn scan(&mut self, cursor: u64, count: usize, pattern: Option<&[u8]>, key_type: Option<&[u8]>) -> (u64, Vec<Box<[u8]>>) {
+        let mut next_cursor = 0;
+        let mut last: Option<&u64> = None;
+        let mut candidates = Vec::new();
+        for (hash, key) in self.scan_order.range((cursor, Box::default())..) {
+            // Keys sharing the hash must be returned together, the cursor can't split them
+            if let Some(last) = last.filter(|last| candidates.len() >= count.max(1) && *last != hash) {
+                // Nothing can follow the largest hash, so the iteration ends there
+                next_cursor = last.checked_add(1).unwrap_or(0);
+                break;
+            }
+            last = Some(hash);
+            candidates.push(key);
+        }
+
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut keys = Vec::new();
+        for key in candidates {
+            let value = &self.data[key];
+            if value.is_expired(now) {
+                expired.push(key.clone());
+            } else if pattern.iter().all(|p| glob_match(p, key, false))
+                && key_type.iter().all(|t| t.eq_ignore_ascii_case(value.type_name().as_bytes()))
+            {
+                keys.push(key.clone());
+            }
+        }
+        for key in expired {
+            self.expire(&key);
+        }
+        (next_cursor, keys)
+    }
+
+    /// Samples keys with expiry and removes the expired ones.
+    ///
+    /// Keeps sampling while more than `ACTIVE_EXPIRE_ACCEPTABLE_STALE` percent of sampled keys were
+    /// expired, but never past the `deadline`.
+    pub fn active_expire_cycle(&mut self, deadline: Instant) -> ExpireCycleResult {
+        let mut result = ExpireCycleResult::default();
+        if self.expires.is_empty() {
+            self.avg_ttl = 0;
+        }
+        while !self.expires.is_empty() {
+            let now = Instant::now();
+            let mut sampled = 0;
+            let mut expired = 0;
+            let mut ttl_sum = 0;
+            let mut ttl_samples = 0;
+            for _ in 0..ACTIVE_EXPIRE_KEYS_PER_LOOP.min(self.expires.len()) {
+                let idx = self.random_index(self.expires.len());
+                let key = &self.expires[idx];
+                sampled += 1;
+                let value = &self.data[key];
+                if value.is_expired(now) {
+                    let key = key.clone();
+                    self.expire(&key);
+                    expired += 1;
+                } else if let Some(at) = value.expiry_at() {
+                    ttl_sum += (at - now).as_millis() as u64;
+                    ttl_samples += 1;
+                }
+                if self.expires.is_empty() {
+                    break;
+                }
+            }
+            if let Some(avg_ttl) = ttl_sum.checked_div(ttl_samples) {
+                // Smooth the estimate over the last ~50 iterations, the same way Redis does
+                self.avg_ttl = if self.avg_ttl == 0 { avg_ttl } else { self.avg_ttl / 50 * 49 + avg_ttl / 50 };
+            }
+            result.sampled += sampled;
+            result.expired += expired;
+
+            if expired * 100 <= sampled * ACTIVE_EXPIRE_ACCEPTABLE_STALE {
+                break;
+            }
+            if Instant::now() >= deadline {
+                result.timed_out = true;
+                break;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::pubsub::PubSub;
+
+    fn data() -> Data {
+        let notifier = Arc::new(Notifier::new(Arc::new(Mutex::new(PubSub::default())), 0));
+        Data::new(0, notifier, Arc::new(Mutex::new(Tracking::new(0))))
+    }
+
+    #[test]
+    fn scan_returns_keys_present_for_whole_iteration() {
+        let mut data = data();
+        let stable: HashSet<Vec<u8>> = (0..200).map(|i| format!("stable:{i}").into_bytes()).collect();
+        for key in &stable {
+            data.set(key, Bytes::from_static(b"value"), None);
+        }
+        for i in 0..100 {
+            data.set(format!("removed:{i}").as_bytes(), Bytes::from_static(b"value"), None);
+        }
+
+        let mut returned = HashSet::new();
+        let (mut cursor, mut calls) = (0, 0);
+        loop {
+            let (next, keys) = data.scan(cursor, 10, None, None);
+            returned.extend(keys.into_iter().map(Vec::from));
+            // Keys come and go between the calls, resizing the map
+            data.del(format!("removed:{calls}").as_bytes());
+            for i in 0..20 {
+                data.set(format!("added:{calls}:{i}").as_bytes(), Bytes::from_static(b"value"), None);
+            }
+            calls += 1;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!(stable.is_subset(&returned), "{} stable keys missing", stable.difference(&returned).count());
+        assert!(calls > 20, "{calls} calls");
+    }
+
+    #[test]
+    fn scan_from_last_cursor_ends_iteration() {
+        let mut data = data();
+        data.set(b"key", Bytes::from_static(b"value"), None);
+        assert_eq!(data.scan(u64::MAX, 10, None, None).0, 0);
+        let (cursor, keys) = data.scan(0, 10, None, None);
+        assert_eq!((cursor, keys), (0, vec![Box::from(&b"key"[..])]));
+    }
+
+    /// Database with `expired` keys past their expiry and `alive` keys expiring in an hour
+    fn data_with_expiring_keys(expired: usize, alive: usize) -> Data {
+        let mut data = data();
+        for i in 0..expired {
+            data.set(format!("expired:{i}").as_bytes(), Bytes::from_static(b"value"), Some(Duration::from_millis(1)));
+        }
+        for i in 0..alive {
+            data.set(format!("alive:{i}").as_bytes(), Bytes::from_static(b"value"), Some(Duration::from_secs(3600)));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        data
+    }
+
+    #[test]
+    fn active_expire_keeps_sampling_while_many_keys_are_expired() {
+        let mut data = data_with_expiring_keys(1000, 0);
+        let result = data.active_expire_cycle(Instant::now() + Duration::from_secs(60));
+        assert_eq!((result.sampled, result.expired, result.timed_out), (1000, 1000, false));
+        assert_eq!(data.expires_count(), 0);
+
+        // Once few of the sampled keys are expired, one loop is enough
+        let mut data = data_with_expiring_keys(0, 1000);
+        let result = data.active_expire_cycle(Instant::now() + Duration::from_secs(60));
+        assert_eq!((result.sampled, result.expired, result.timed_out), (ACTIVE_EXPIRE_KEYS_PER_LOOP, 0, false));
+        assert_eq!(data.expires_count(), 1000);
+    }
+
+    #[test]
+    fn active_expire_stops_at_deadline() {
+        let mut data = data_with_expiring_keys(1000, 0);
+        let result = data.active_expire_cycle(Instant::now());
+        assert_eq!((result.sampled, result.expired, result.timed_out), (ACTIVE_EXPIRE_KEYS_PER_LOOP, ACTIVE_EXPIRE_KEYS_PER_LOOP, true));
+        assert_eq!(data.expires_count(), 1000 - ACTIVE_EXPIRE_KEYS_PER_LOOP);
     }
 }
@@ -0,0 +1,77 @@
+use std::fmt;
+
+use crate::resp::RespType;
+
+/// Error of a single command, replied to the client while the connection stays open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CommandError {
+    /// Generic error, the message is replied with the `ERR` prefix
+    Err(String),
+    UnknownCommand { name: String, args: String },
+    UnknownSubcommand { container: String, name: String },
+    WrongArity(String),
+    NoAuth,
+    /// Write command sent to a read-only replica
+    ReadOnly,
+    ExecAbort,
+    NoPerm(String),
+    WrongPass,
+    NoProto,
+}
+
+impl CommandError {
+    pub(crate) fn err(message: impl Into<String>) -> Self {
+        Self::Err(message.into())
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Err(message) => write!(f, "ERR {message}"),
+            Self::UnknownCommand { name, args } => {
+                write!(f, "ERR unknown command '{name}', with args beginning with: {args}")
+            }
+            Self::UnknownSubcommand { container, name } => {
+                write!(f, "ERR unknown subcommand '{name}'. Try {} HELP.", container.to_ascii_uppercase())
+            }
+            Self::WrongArity(name) => write!(f, "ERR wrong number of arguments for '{name}' command"),
+            Self::NoAuth => f.write_str("NOAUTH Authentication required."),
+            Self::ReadOnly => f.write_str("READONLY You can't write against a read only replica."),
+            Self::ExecAbort => f.write_str("EXECABORT Transaction discarded because of previous errors."),
+            Self::NoPerm(message) => write!(f, "NOPERM {message}"),
+            Self::WrongPass => f.write_str("WRONGPASS invalid username-password pair or user is disabled."),
+            Self::NoProto => f.write_str("NOPROTO unsupported protocol version"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Errors raised by `bail!` and friends in command handlers are generic `ERR` errors
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Self>() {
+            Ok(err) => err,
+            Err(err) => Self::Err(err.to_string()),
+        }
+    }
+}
+
+/// Malformed request, the error is replied and then the connection is closed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProtocolError(pub(crate) String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ERR Protocol error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<CommandError> for RespType {
+    fn from(err: CommandError) -> Self {
+        RespType::SimpleError(err.to_string())
+    }
+}
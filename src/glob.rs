@@ -0,0 +1,143 @@
+/// Matches `string` against glob-style `pattern` with the same semantics as Redis.
+///
+/// Supported syntax: `*` (any sequence), `?` (any single byte), `[abc]`, `[^abc]`, `[a-z]`
+/// (byte classes) and `\x` (escaping).
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| if nocase { a.eq_ignore_ascii_case(&b) } else { a == b };
+
+    let mut p = 0;
+    let mut s = 0;
+    // Position in pattern after the last `*` and position in string it's currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                // Collapse consecutive stars
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+                p += 1;
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, s));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                true
+            }
+            Some(b'[') => {
+                let (matched, len) = match_class(&pattern[p + 1..], string[s], nocase);
+                p += 1 + len;
+                matched
+            }
+            Some(b'\\') if p + 1 < pattern.len() => {
+                p += 2;
+                eq(pattern[p - 1], string[s])
+            }
+            Some(&c) => {
+                p += 1;
+                eq(c, string[s])
+            }
+            None => false,
+        };
+
+        if matched {
+            s += 1;
+        } else if let Some((star_p, star_s)) = backtrack {
+            // Let the last star consume one more byte and retry
+            p = star_p;
+            s = star_s + 1;
+            backtrack = Some((star_p, s));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches single byte against class body (pattern right after the opening `[`).
+///
+/// Returns whether it matched and the length of the class body including the closing `]`.
+/// Same as Redis, unterminated class extends to the end of the pattern.
+fn match_class(class: &[u8], c: u8, nocase: bool) -> (bool, usize) {
+    let fold = |b: u8| if nocase { b.to_ascii_lowercase() } else { b };
+    let c = fold(c);
+
+    let mut i = 0;
+    let negate = class.first() == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    loop {
+        match class.get(i) {
+            None => return (matched != negate, i),
+            Some(b']') => break,
+            Some(b'\\') if i + 1 < class.len() => {
+                matched |= fold(class[i + 1]) == c;
+                i += 2;
+            }
+            Some(&start) if class.get(i + 1) == Some(&b'-') && i + 2 < class.len() && class[i + 2] != b']' => {
+                let (mut start, mut end) = (fold(start), fold(class[i + 2]));
+                if start > end {
+                    std::mem::swap(&mut start, &mut end);
+                }
+                matched |= (start..=end).contains(&c);
+                i += 3;
+            }
+            Some(&b) => {
+                matched |= fold(b) == c;
+                i += 1;
+            }
+        }
+    }
+    (matched != negate, i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("h?llo", "hello") && matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo") && !matches("h?llo", "heello"));
+        assert!(matches("h*llo", "hllo") && matches("h*llo", "heeeello"));
+        assert!(matches("*", "") && matches("**", "anything") && matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b", "abc") && !matches("", "a"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hello") && matches("h[ae]llo", "hallo") && !matches("h[ae]llo", "hillo"));
+        assert!(matches("[a-z]", "m") && matches("[z-a]", "m") && !matches("[a-z]", "M"));
+        assert!(glob_match(b"[a-z]", b"M", true));
+        assert!(matches("h[^e]llo", "hallo") && !matches("h[^e]llo", "hello"));
+        assert!(matches("[\\]]", "]") && matches("[a-]", "-"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*") && !matches("\\*", "a"));
+        assert!(matches("a\\?", "a?") && !matches("a\\?", "ab"));
+        assert!(matches("\\[a]", "[a]"));
+        // Trailing backslash matches itself
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_class_extends_to_end() {
+        assert!(matches("[abc", "a") && matches("[abc", "c") && !matches("[abc", "d"));
+        assert!(!matches("[abc", "ab"));
+        assert!(matches("x[^a", "xb") && !matches("x[^a", "xa"));
+        assert!(!matches("[", "a"));
+    }
+}
@@ -1,9 +1,25 @@
+mod acl;
+mod client;
+mod commands;
+mod config;
 mod connection;
 mod data;
+mod error;
+mod glob;
+mod net;
+mod notify;
+mod output;
+mod pubsub;
 mod replication_connection;
 mod resp;
 mod server;
+mod sha256;
+#[cfg(test)]
+mod testing;
+mod tracking;
 
+pub use config::{BindAddr, Config};
 pub use connection::Connection;
+pub use net::{bind_tcp, set_tcp_keepalive, PeerAddr, TCP_KEEPALIVE_SUPPORTED};
 pub use replication_connection::ReplicationConnection;
-pub use server::{ReplicationMode, Server};
+pub use server::{ClientSlot, ReplicationMode, Server};
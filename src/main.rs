@@ -1,26 +1,22 @@
-use std::net::{IpAddr, SocketAddr};
+use std::fs::Permissions;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Context};
-use tokio::net::{TcpListener, TcpStream};
+use anyhow::{bail, ensure, Context};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 
-use redis_starter_rust::{Connection, ReplicationConnection, ReplicationMode, Server};
-
-struct Config {
-    host: IpAddr,
-    port: u16,
-    replication: ReplicationMode,
-}
+use redis_starter_rust::{bind_tcp, set_tcp_keepalive, Config, Connection, PeerAddr, ReplicationConnection, ReplicationMode, Server, TCP_KEEPALIVE_SUPPORTED};
 
 fn parse_args() -> anyhow::Result<Config> {
-    let mut args = std::env::args();
+    let mut args = std::env::args().peekable();
     args.next()
         .context("Expected first arg (path of executable)")?;
 
-    let mut config = Config {
-        host: "127.0.0.1".parse().unwrap(),
-        port: 6379,
-        replication: ReplicationMode::Master,
-    };
+    let mut config = Config::default();
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
@@ -31,21 +27,125 @@ fn parse_args() -> anyhow::Result<Config> {
                     .parse()
                     .context("Invalid value for port arg")?
             }
-            "--replicaof" => {
-                let mut host = args
+            // Takes all the following values, or a single one listing the addresses separated by spaces
+            "--bind" => {
+                let mut bind = Vec::new();
+                while let Some(value) = args.next_if(|value| !value.starts_with("--")) {
+                    for addr in value.split_ascii_whitespace() {
+                        bind.push(addr.parse().with_context(|| format!("Invalid bind address '{addr}'"))?);
+                    }
+                }
+                ensure!(!bind.is_empty(), "Argument bind is missing a value");
+                config.bind = bind;
+            }
+            "--protected-mode" => {
+                config.protected_mode = match args.next().context("Argument protected-mode is missing a value")?.as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => bail!("Invalid value for protected-mode arg, expected yes or no"),
+                };
+            }
+            "--unixsocket" => {
+                config.unixsocket = Some(args.next().context("Argument unixsocket is missing a value")?.into());
+            }
+            "--unixsocketperm" => {
+                let perm = args.next().context("Argument unixsocketperm is missing a value")?;
+                let perm = u32::from_str_radix(&perm, 8).context("Invalid value for unixsocketperm arg")?;
+                ensure!(perm <= 0o777, "Argument unixsocketperm must be at most 777");
+                config.unixsocketperm = Some(perm);
+            }
+            "--databases" => {
+                config.databases = args
                     .next()
-                    .context("Argument `replicaof` missing host value")?;
-                let port = args
+                    .context("Argument databases is missing a value")?
+                    .parse()
+                    .context("Invalid value for databases arg")?;
+                ensure!(config.databases > 0, "Argument databases must be positive");
+            }
+            "--notify-keyspace-events" => {
+                config.notify_keyspace_events = args
+                    .next()
+                    .context("Argument notify-keyspace-events is missing a value")?;
+            }
+            "--requirepass" => {
+                let password = args.next().context("Argument requirepass is missing a value")?;
+                config.requirepass = Some(password).filter(|p| !p.is_empty());
+            }
+            "--masterauth" => {
+                let password = args.next().context("Argument masterauth is missing a value")?;
+                config.masterauth = Some(password).filter(|p| !p.is_empty());
+            }
+            "--aclfile" => {
+                config.aclfile = Some(args.next().context("Argument aclfile is missing a value")?.into());
+            }
+            "--proto-max-bulk-len" | "--proto-max-multibulk-len" | "--proto-max-nesting" | "--proto-max-line-len" => {
+                let name = &arg[2..];
+                let value: usize = args
+                    .next()
+                    .with_context(|| format!("Argument {name} is missing a value"))?
+                    .parse()
+                    .with_context(|| format!("Invalid value for {name} arg"))?;
+                ensure!(value > 0, "Argument {name} must be positive");
+                let limit = match name {
+                    "proto-max-bulk-len" => &mut config.proto_max_bulk_len,
+                    "proto-max-multibulk-len" => &mut config.proto_max_multibulk_len,
+                    "proto-max-nesting" => &mut config.proto_max_nesting,
+                    _ => &mut config.proto_max_line_len,
+                };
+                *limit = value;
+            }
+            "--maxclients" => {
+                config.maxclients = args
+                    .next()
+                    .context("Argument maxclients is missing a value")?
+                    .parse()
+                    .context("Invalid value for maxclients arg")?;
+                ensure!(config.maxclients > 0, "Argument maxclients must be positive");
+            }
+            "--timeout" | "--tcp-keepalive" => {
+                let name = &arg[2..];
+                let value = args
                     .next()
-                    .context("Argument `replicaof` missing port value")?
+                    .with_context(|| format!("Argument {name} is missing a value"))?
                     .parse()
-                    .context("Invalid value for port arg")?;
-                if host == "localhost" {
-                    // TODO: perform DNS resolution instead
-                    host = String::from("127.0.0.1");
+                    .with_context(|| format!("Invalid value for {name} arg"))?;
+                if name == "timeout" {
+                    config.timeout = value;
+                } else {
+                    ensure!(value == 0 || TCP_KEEPALIVE_SUPPORTED, "TCP keepalive is not supported on this platform");
+                    config.tcp_keepalive = value;
                 }
-                let addr = SocketAddr::new(host.parse()?, port);
-                config.replication = ReplicationMode::Slave { addr };
+            }
+            "--tracking-table-max-keys" => {
+                config.tracking_table_max_keys = args
+                    .next()
+                    .context("Argument tracking-table-max-keys is missing a value")?
+                    .parse()
+                    .context("Invalid value for tracking-table-max-keys arg")?;
+            }
+            "--client-output-buffer-limit" => {
+                let value = args.next().context("Argument client-output-buffer-limit is missing a value")?;
+                config
+                    .client_output_buffer_limit
+                    .parse(&value)
+                    .map_err(anyhow::Error::msg)
+                    .context("Invalid value for client-output-buffer-limit arg")?;
+            }
+            // Either `--replicaof host port` or `--replicaof "host port"`
+            "--replicaof" => {
+                let value = args
+                    .next()
+                    .context("Argument `replicaof` missing host value")?;
+                let (host, port) = match value.split_once(' ') {
+                    Some((host, port)) => (host.to_owned(), port.trim().to_owned()),
+                    None => (value, args.next().context("Argument `replicaof` missing port value")?),
+                };
+                let port = port.parse().context("Invalid value for port arg")?;
+                config.replication = ReplicationMode::Slave { host, port };
+            }
+            // Needs a TLS implementation, which this build doesn't have
+            "--tls-port" | "--tls-cert-file" | "--tls-key-file" | "--tls-ca-cert-file" | "--tls-auth-clients" | "--tls-replication" => {
+                bail!("Argument {} is not supported, the server is built without TLS", &arg[2..])
             }
             _ => bail!("Unrecognized argument {arg}"),
         }
@@ -53,41 +153,199 @@ fn parse_args() -> anyhow::Result<Config> {
     Ok(config)
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let config = parse_args()?;
-    let addr = SocketAddr::new(config.host, config.port);
-    let listener = TcpListener::bind(addr).await?;
-    let server = Server::new(config.replication, addr);
-
-    if let ReplicationMode::Slave { addr } = server.replication {
-        let server = server.clone();
-        let mut stream = TcpStream::connect(addr)
-            .await
-            .context("Connceting to master node failed")
-            .unwrap();
-        tokio::spawn(async move {
-            let conn = ReplicationConnection::new(&mut stream, server);
-            match conn.run_replication_loop().await {
-                Ok(_) => {}
-                Err(err) => eprintln!("Processing of stream failed: {}", err),
-            };
-        });
-    }
+/// Serves the client until it disconnects
+async fn serve<R, W>(reader: R, writer: W, addr: PeerAddr, laddr: PeerAddr, server: Server)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let conn = Connection::new(reader, writer, addr, laddr, server);
+    match conn.run_processing_loop().await {
+        Ok(_) => {}
+        Err(err) => eprintln!("Processing of stream failed: {}", err),
+    };
+}
+
+/// Delay before connecting to our master again, same as the period of the replication cron in Redis
+const MASTER_RECONNECT_PERIOD: Duration = Duration::from_secs(1);
+
+const MAXCLIENTS_REACHED: &[u8] = b"-ERR max number of clients reached\r\n";
 
+const PROTECTED_MODE_DENIED: &[u8] = b"-DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.\r\n";
+
+/// Replies with the error and closes the connection
+fn reject<S: AsyncWrite + Unpin + Send + 'static>(mut stream: S, error: &'static [u8]) {
+    // Written by a separate task, so a client not reading its socket doesn't stall accepting
+    tokio::spawn(async move {
+        let _ = stream.write_all(error).await;
+    });
+}
+
+async fn accept_tcp(listener: TcpListener, server: Server) {
     loop {
         match listener.accept().await {
             Ok((mut stream, addr)) => {
+                let Some(slot) = server.accept_client() else {
+                    reject(stream, MAXCLIENTS_REACHED);
+                    continue;
+                };
+                if server.is_protected_from(addr.ip()) {
+                    reject(stream, PROTECTED_MODE_DENIED);
+                    continue;
+                }
+                let keepalive = server.tcp_keepalive();
+                if keepalive > 0 {
+                    if let Err(err) = set_tcp_keepalive(&stream, keepalive) {
+                        eprintln!("Failed to enable TCP keepalive for {addr}: {err}");
+                    }
+                }
                 let server = server.clone();
                 tokio::spawn(async move {
-                    let conn = Connection::new(&mut stream, addr, server);
-                    match conn.run_processing_loop().await {
-                        Ok(_) => {}
-                        Err(err) => eprintln!("Processing of stream failed: {}", err),
+                    let laddr = match stream.local_addr() {
+                        Ok(laddr) => laddr,
+                        Err(err) => return eprintln!("couldn't get client: {:?}", err),
                     };
+                    let (reader, writer) = stream.split();
+                    serve(reader, writer, addr.into(), laddr.into(), server).await;
+                    drop(slot);
+                });
+            }
+            Err(e) => eprintln!("couldn't get client: {:?}", e),
+        }
+    }
+}
+
+async fn accept_unix(listener: UnixListener, path: Arc<Path>, server: Server) {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                let Some(slot) = server.accept_client() else {
+                    reject(stream, MAXCLIENTS_REACHED);
+                    continue;
+                };
+                let server = server.clone();
+                let addr = PeerAddr::Unix(Arc::clone(&path));
+                tokio::spawn(async move {
+                    let (reader, writer) = stream.split();
+                    serve(reader, writer, addr.clone(), addr, server).await;
+                    drop(slot);
                 });
             }
             Err(e) => eprintln!("couldn't get client: {:?}", e),
         }
     }
 }
+
+/// Replicates our master, connecting to it again whenever the link breaks
+async fn replicate(host: String, port: u16, server: Server) {
+    loop {
+        // Resolved on every attempt, so DNS changes of the master's address are picked up
+        match TcpStream::connect((host.as_str(), port)).await {
+            Ok(mut stream) => match stream.peer_addr() {
+                Ok(addr) => {
+                    // Our end is only shown by `CLIENT LIST`, so the link doesn't fail without it
+                    let laddr = stream.local_addr().unwrap_or_else(|_| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), server.port));
+                    let (reader, writer) = stream.split();
+                    let conn = ReplicationConnection::new(reader, writer, addr, laddr, server.clone());
+                    match conn.run_replication_loop().await {
+                        Ok(_) => eprintln!("Connection with master lost."),
+                        Err(err) => eprintln!("Processing of stream failed: {}", err),
+                    };
+                }
+                Err(err) => eprintln!("Connecting to master {host}:{port} failed: {err}"),
+            },
+            Err(err) => eprintln!("Connecting to master {host}:{port} failed: {err}"),
+        }
+        tokio::time::sleep(MASTER_RECONNECT_PERIOD).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = parse_args()?;
+    let server = Server::new(config)?;
+    ensure!(
+        server.port != 0 || server.unixsocket.is_some(),
+        "Configured to not listen anywhere, exiting."
+    );
+    let mut listeners = Vec::new();
+    // Port 0 disables TCP, same as in Redis
+    for bind in server.bind.iter().filter(|_| server.port != 0) {
+        let addr = SocketAddr::new(bind.ip, server.port);
+        match bind_tcp(addr) {
+            Ok(listener) => listeners.push(tokio::spawn(accept_tcp(listener, server.clone()))),
+            // E.g. IPv6 disabled on the host
+            Err(err) if bind.optional => eprintln!("Warning: Could not create server TCP listening socket {addr}: {err}"),
+            Err(err) => bail!("Could not create server TCP listening socket {addr}: {err}"),
+        }
+    }
+    ensure!(
+        !listeners.is_empty() || server.unixsocket.is_some(),
+        "Failed listening on port {} (tcp), aborting.",
+        server.port
+    );
+    if let Some(path) = &server.unixsocket {
+        // Socket file left behind by a previous run would make binding fail
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).context("Failed opening Unix socket")?;
+        if let Some(perm) = server.unixsocketperm {
+            std::fs::set_permissions(path, Permissions::from_mode(perm)).context("Failed to set permissions of Unix socket")?;
+        }
+        listeners.push(tokio::spawn(accept_unix(listener, path.as_path().into(), server.clone())));
+    }
+    server.spawn_active_expire();
+    server.spawn_clients_cron();
+
+    if let ReplicationMode::Slave { host, port } = &server.replication {
+        tokio::spawn(replicate(host.clone(), *port, server.clone()));
+    }
+
+    for listener in listeners {
+        listener.await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    const PING: &[u8] = b"*1\r\n$4\r\nping\r\n";
+
+    #[tokio::test]
+    async fn clients_are_served_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("redis-unixsocket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("redis.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = Server::new(Config::default()).unwrap();
+        tokio::spawn(accept_unix(listener, path.as_path().into(), server));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(PING).await.unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn master_hostname_is_resolved_on_every_reconnect() {
+        let master = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = master.local_addr().unwrap().port();
+        let server = Server::new(Config::default()).unwrap();
+        tokio::spawn(replicate(String::from("localhost"), port, server));
+
+        // Handshake starts on the first connection, and again after the link breaks
+        for _ in 0..2 {
+            let (mut stream, _) = tokio::time::timeout(MASTER_RECONNECT_PERIOD * 3, master.accept()).await.unwrap().unwrap();
+            let mut request = [0; PING.len()];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, PING);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Address of either end of a client connection
+#[derive(Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Path of the Unix socket, clients connected through it have no address of their own
+    Unix(Arc<Path>),
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+/// Same as Redis, Unix socket path is shown with port 0 in `CLIENT LIST`
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "{}:0", path.display()),
+        }
+    }
+}
+
+impl fmt::Debug for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => fmt::Debug::fmt(addr, f),
+            Self::Unix(path) => fmt::Debug::fmt(path, f),
+        }
+    }
+}
+
+/// Socket options missing from std, with the values of the architectures using the generic Linux ABI
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64")
+))]
+mod sys {
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::raw::{c_int, c_void};
+
+    pub const SOL_SOCKET: c_int = 1;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const IPPROTO_TCP: c_int = 6;
+    pub const TCP_KEEPIDLE: c_int = 4;
+    pub const TCP_KEEPINTVL: c_int = 5;
+    pub const TCP_KEEPCNT: c_int = 6;
+    pub const IPPROTO_IPV6: c_int = 41;
+    pub const IPV6_V6ONLY: c_int = 26;
+
+    pub const TCP_KEEPALIVE_SUPPORTED: bool = true;
+
+    extern "C" {
+        fn setsockopt(socket: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+        #[cfg(test)]
+        fn getsockopt(socket: c_int, level: c_int, name: c_int, value: *mut c_void, len: *mut u32) -> c_int;
+    }
+
+    pub fn set(socket: &impl AsRawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+        let len = std::mem::size_of_val(&value) as u32;
+        // SAFETY: the descriptor is owned by the socket, and the value outlives the call
+        let result = unsafe { setsockopt(socket.as_raw_fd(), level, name, (&value as *const c_int).cast(), len) };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(socket: &impl AsRawFd, level: c_int, name: c_int) -> io::Result<c_int> {
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as u32;
+        // SAFETY: the descriptor is owned by the socket, and the value and its length outlive the call
+        let result = unsafe { getsockopt(socket.as_raw_fd(), level, name, (&mut value as *mut c_int).cast(), &mut len) };
+        if result == 0 {
+            Ok(value)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn set_only_v6(socket: &impl AsRawFd) -> io::Result<()> {
+        set(socket, IPPROTO_IPV6, IPV6_V6ONLY, 1)
+    }
+
+    pub fn set_tcp_keepalive(socket: &impl AsRawFd, interval: u64) -> io::Result<()> {
+        let interval = i32::try_from(interval).unwrap_or(i32::MAX);
+        set(socket, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        // Same as Redis: first probe after `interval`, then 3 probes spread over another `interval`
+        set(socket, IPPROTO_TCP, TCP_KEEPIDLE, interval)?;
+        set(socket, IPPROTO_TCP, TCP_KEEPINTVL, (interval / 3).max(1))?;
+        set(socket, IPPROTO_TCP, TCP_KEEPCNT, 3)?;
+        Ok(())
+    }
+}
+
+/// Elsewhere the values of the options aren't known, so they are left to the system defaults
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64")
+)))]
+mod sys {
+    use std::io;
+
+    pub const TCP_KEEPALIVE_SUPPORTED: bool = false;
+
+    pub fn set_only_v6<T>(_socket: &T) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set_tcp_keepalive<T>(_socket: &T, _interval: u64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "TCP keepalive options are not supported on this platform"))
+    }
+}
+
+/// Backlog of pending connections, same as the default `tcp-backlog` of Redis
+const TCP_BACKLOG: u32 = 511;
+
+/// Listens on the address, IPv6 sockets don't accept IPv4 connections so `*` and `::*` can be bound together.
+/// Where `IPV6_V6ONLY` can't be set, binding `::*` after `*` fails unless the system defaults to IPv6 only sockets.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        let socket = TcpSocket::new_v6()?;
+        sys::set_only_v6(&socket)?;
+        socket
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(TCP_BACKLOG)
+}
+
+/// Whether `set_tcp_keepalive` can configure the probes on this platform
+pub const TCP_KEEPALIVE_SUPPORTED: bool = sys::TCP_KEEPALIVE_SUPPORTED;
+
+/// Enables TCP keepalive probes, so dead peers are detected after roughly `interval` seconds (`tcp-keepalive`).
+/// Fails with `Unsupported` where the options can't be set.
+pub fn set_tcp_keepalive(stream: &TcpStream, interval: u64) -> io::Result<()> {
+    sys::set_tcp_keepalive(stream, interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keepalive_options_are_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        if !TCP_KEEPALIVE_SUPPORTED {
+            assert_eq!(set_tcp_keepalive(&stream, 30).unwrap_err().kind(), io::ErrorKind::Unsupported);
+            return;
+        }
+        set_tcp_keepalive(&stream, 30).unwrap();
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64")
+        ))]
+        {
+            assert_eq!(sys::get(&stream, sys::SOL_SOCKET, sys::SO_KEEPALIVE).unwrap(), 1);
+            assert_eq!(sys::get(&stream, sys::IPPROTO_TCP, sys::TCP_KEEPIDLE).unwrap(), 30);
+            assert_eq!(sys::get(&stream, sys::IPPROTO_TCP, sys::TCP_KEEPINTVL).unwrap(), 10);
+            assert_eq!(sys::get(&stream, sys::IPPROTO_TCP, sys::TCP_KEEPCNT).unwrap(), 3);
+        }
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64")
+    ))]
+    #[tokio::test]
+    async fn ipv4_and_ipv6_are_bound_on_same_port() {
+        let v4 = bind_tcp(SocketAddr::from(([0, 0, 0, 0], 0))).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = match bind_tcp(SocketAddr::from(([0u16; 8], port))) {
+            Err(err) if err.kind() != io::ErrorKind::AddrInUse => {
+                eprintln!("Skipping test, IPv6 is unavailable: {err}");
+                return;
+            }
+            v6 => v6.unwrap(),
+        };
+        assert_eq!(sys::get(&v6, sys::IPPROTO_IPV6, sys::IPV6_V6ONLY).unwrap(), 1);
+        let _v6_stream = TcpStream::connect(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port))).await.unwrap();
+        let (_, from) = v6.accept().await.unwrap();
+        assert!(from.is_ipv6());
+        let _v4_stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await.unwrap();
+        let (_, from) = v4.accept().await.unwrap();
+        assert!(from.is_ipv4());
+    }
+}
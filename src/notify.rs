@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::pubsub::PubSub;
+
+/// Publish `__keyspace@<db>__:<key>` notifications
+pub(crate) const KEYSPACE: u32 = 1 << 0;
+/// Publish `__keyevent@<db>__:<event>` notifications
+pub(crate) const KEYEVENT: u32 = 1 << 1;
+pub(crate) const GENERIC: u32 = 1 << 2;
+pub(crate) const STRING: u32 = 1 << 3;
+pub(crate) const LIST: u32 = 1 << 4;
+pub(crate) const SET: u32 = 1 << 5;
+pub(crate) const HASH: u32 = 1 << 6;
+pub(crate) const ZSET: u32 = 1 << 7;
+pub(crate) const EXPIRED: u32 = 1 << 8;
+pub(crate) const EVICTED: u32 = 1 << 9;
+pub(crate) const STREAM: u32 = 1 << 10;
+pub(crate) const KEY_MISS: u32 = 1 << 11;
+pub(crate) const MODULE: u32 = 1 << 12;
+pub(crate) const NEW: u32 = 1 << 13;
+/// Classes included in the `A` alias
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+const FLAG_CHARS: [(char, u32); 14] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('m', KEY_MISS),
+    ('n', NEW),
+];
+
+/// Parses `notify-keyspace-events` flag string, returning `None` for unknown flags
+pub(crate) fn parse_flags(flags: &str) -> Option<u32> {
+    flags.chars().try_fold(0, |acc, c| match c {
+        'A' => Some(acc | ALL),
+        c => FLAG_CHARS.iter().find(|(flag, _)| *flag == c).map(|(_, bit)| acc | bit),
+    })
+}
+
+/// Formats flags back to the canonical `notify-keyspace-events` string
+pub(crate) fn flags_to_string(flags: u32) -> String {
+    let mut result = String::new();
+    let mut remaining = flags;
+    if flags & ALL == ALL {
+        result.push('A');
+        remaining &= !ALL;
+    }
+    for (c, bit) in FLAG_CHARS {
+        if remaining & bit != 0 {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Publishes keyspace event notifications enabled by `notify-keyspace-events`
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    pubsub: Arc<Mutex<PubSub>>,
+    flags: AtomicU32,
+}
+
+impl Notifier {
+    pub fn new(pubsub: Arc<Mutex<PubSub>>, flags: u32) -> Self {
+        Self {
+            pubsub,
+            flags: AtomicU32::new(flags),
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flags(&self, flags: u32) {
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    /// Notifies about `event` of the `class` which happened to `key` in database `db`
+    pub fn notify(&self, class: u32, event: &str, key: &[u8], db: usize) {
+        let flags = self.flags();
+        if flags & class == 0 || flags & (KEYSPACE | KEYEVENT) == 0 {
+            return;
+        }
+        let pubsub = self.pubsub.lock().unwrap();
+        if flags & KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{db}__:").into_bytes();
+            channel.extend_from_slice(key);
+            pubsub.publish(&channel, event.as_bytes());
+        }
+        if flags & KEYEVENT != 0 {
+            let channel = format!("__keyevent@{db}__:{event}");
+            pubsub.publish(channel.as_bytes(), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{array, bulk, ok, server, TestClient};
+
+    #[test]
+    fn flags_round_trip() {
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("K$"), Some(KEYSPACE | STRING));
+        assert_eq!(parse_flags("gxE"), Some(GENERIC | EXPIRED | KEYEVENT));
+        assert_eq!(parse_flags("KEA"), Some(KEYSPACE | KEYEVENT | ALL));
+        // `A` doesn't include key misses and new keys
+        assert_eq!(parse_flags("A").map(|flags| flags & (KEY_MISS | NEW)), Some(0));
+        assert_eq!(parse_flags("KQ"), None);
+        assert_eq!(parse_flags("k"), None);
+        assert_eq!(flags_to_string(parse_flags("EKA").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("xg$E").unwrap()), "g$xE");
+        assert_eq!(flags_to_string(parse_flags("Am").unwrap()), "Am");
+    }
+
+    #[tokio::test]
+    async fn events_are_filtered_by_flags() {
+        let server = server(|_| {});
+        let mut client = TestClient::connect(&server);
+        let mut subscriber = TestClient::connect(&server);
+        subscriber.cmd(&["PSUBSCRIBE", "__key*__:*"]).await;
+        let message = |channel: &str, payload: &str| Some(array([bulk("pmessage"), bulk("__key*__:*"), bulk(channel), bulk(payload)]));
+
+        // Only keyspace notifications of string commands
+        assert_eq!(client.cmd(&["CONFIG", "SET", "notify-keyspace-events", "K$"]).await, ok());
+        client.cmd(&["SET", "key", "value"]).await;
+        client.cmd(&["DEL", "key"]).await;
+        client.cmd(&["SET", "other", "value"]).await;
+        assert_eq!(subscriber.read().await, message("__keyspace@0__:key", "set"));
+        assert_eq!(subscriber.read().await, message("__keyspace@0__:other", "set"));
+
+        // Only keyevent notifications of generic commands and expired keys
+        assert_eq!(client.cmd(&["CONFIG", "SET", "notify-keyspace-events", "Egx"]).await, ok());
+        client.cmd(&["SET", "expiring", "value", "PX", "10"]).await;
+        client.cmd(&["DEL", "other"]).await;
+        // Same as Redis, `SET` with expiry notifies about `expire` of the generic class too
+        assert_eq!(subscriber.read().await, message("__keyevent@0__:expire", "expiring"));
+        assert_eq!(subscriber.read().await, message("__keyevent@0__:del", "other"));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        client.cmd(&["GET", "expiring"]).await;
+        assert_eq!(subscriber.read().await, message("__keyevent@0__:expired", "expiring"));
+
+        assert_eq!(client.cmd(&["CONFIG", "GET", "notify-keyspace-events"]).await, array([bulk("notify-keyspace-events"), bulk("gxE")]));
+        assert!(client.err(&["CONFIG", "SET", "notify-keyspace-events", "Q"]).await.contains("Invalid event class character"));
+    }
+}
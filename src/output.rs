@@ -0,0 +1,414 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+use crate::resp::RespType;
+
+/// Class of client, each class has its own output buffer limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputClass {
+    Normal,
+    Replica,
+    /// Client subscribed to some channel
+    PubSub,
+}
+
+impl OutputClass {
+    const ALL: [Self; 3] = [Self::Normal, Self::Replica, Self::PubSub];
+
+    fn from_u8(class: u8) -> Self {
+        Self::ALL[class as usize]
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Replica => "slave",
+            Self::PubSub => "pubsub",
+        }
+    }
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "normal" => Some(Self::Normal),
+            "replica" | "slave" => Some(Self::Replica),
+            "pubsub" => Some(Self::PubSub),
+            _ => None,
+        }
+    }
+}
+
+/// Parses memory size with optional unit, e.g. `64mb`
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Output buffer limit of one client class, zero disables the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    /// Client is closed as soon as its output reaches this size
+    pub hard: usize,
+    /// Client is closed once its output stays above this size for `soft_seconds`
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+/// `client-output-buffer-limit` of all the client classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit::default(),
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+impl OutputBufferLimits {
+    fn class(&mut self, class: OutputClass) -> &mut OutputBufferLimit {
+        match class {
+            OutputClass::Normal => &mut self.normal,
+            OutputClass::Replica => &mut self.replica,
+            OutputClass::PubSub => &mut self.pubsub,
+        }
+    }
+
+    /// Parses `<class> <hard> <soft> <soft seconds>` groups, changing only limits of the listed classes
+    pub fn parse(&mut self, value: &str) -> Result<(), String> {
+        let words: Vec<_> = value.split_ascii_whitespace().collect();
+        if words.is_empty() || !words.len().is_multiple_of(4) {
+            return Err(String::from("Wrong number of arguments in buffer limit configuration."));
+        }
+        let mut limits = *self;
+        for group in words.chunks_exact(4) {
+            let class = OutputClass::parse(group[0]).ok_or_else(|| String::from("Invalid client class specified in buffer limit configuration."))?;
+            let invalid = || String::from("Error in hard, soft or soft_seconds setting in buffer limit configuration.");
+            *limits.class(class) = OutputBufferLimit {
+                hard: parse_memory(group[1]).ok_or_else(invalid)?,
+                soft: parse_memory(group[2]).ok_or_else(invalid)?,
+                soft_seconds: group[3].parse().map_err(|_| invalid())?,
+            };
+        }
+        *self = limits;
+        Ok(())
+    }
+
+    /// Value shown by `CONFIG GET client-output-buffer-limit`
+    pub(crate) fn to_config_string(mut self) -> String {
+        OutputClass::ALL
+            .into_iter()
+            .map(|class| {
+                let limit = self.class(class);
+                format!("{} {} {} {}", class.name(), limit.hard, limit.soft, limit.soft_seconds)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Output of a client waiting to be written to it, shared with everyone sending it data
+#[derive(Debug)]
+pub(crate) struct ClientOutput {
+    /// Description of the client used in the log line when it's closed
+    client: String,
+    class: AtomicU8,
+    /// Replies encoded in the output buffer of the connection
+    buffered: AtomicUsize,
+    /// Pushes and replicated commands queued for the connection
+    queued: AtomicUsize,
+    /// When the output exceeded the soft limit, reset once it drops below it
+    soft_limit_since: Mutex<Option<Instant>>,
+    /// Largest output since the last `take_peak`
+    peak: AtomicUsize,
+    limits: Arc<Mutex<OutputBufferLimits>>,
+    closing: AtomicBool,
+    close: Notify,
+}
+
+impl ClientOutput {
+    pub(crate) fn new(client: String, limits: Arc<Mutex<OutputBufferLimits>>) -> Self {
+        Self {
+            client,
+            class: AtomicU8::new(OutputClass::Normal as u8),
+            buffered: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            soft_limit_since: Mutex::new(None),
+            peak: AtomicUsize::new(0),
+            limits,
+            closing: AtomicBool::new(false),
+            close: Notify::new(),
+        }
+    }
+
+    /// Total size of the output not written to the client yet
+    pub(crate) fn len(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed) + self.queued.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn class(&self) -> OutputClass {
+        OutputClass::from_u8(self.class.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_class(&self, class: OutputClass) {
+        self.class.store(class as u8, Ordering::Relaxed);
+    }
+
+    /// Updates size of the connection's output buffer
+    pub(crate) fn set_buffered(&self, len: usize) {
+        self.buffered.store(len, Ordering::Relaxed);
+        self.check_limits();
+    }
+
+    /// Largest output since the last `take_peak`
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Largest output since the last call, starting the next period at the current size
+    pub(crate) fn take_peak(&self) -> usize {
+        self.peak.swap(self.len(), Ordering::Relaxed)
+    }
+
+    /// Closes the client if its output exceeds the limits of its class.
+    ///
+    /// Called whenever the output grows, and periodically for clients whose output stays above the soft limit.
+    pub(crate) fn check_limits(&self) {
+        let len = self.len();
+        self.peak.fetch_max(len, Ordering::Relaxed);
+        let limit = *self.limits.lock().unwrap().class(self.class());
+        let hard_exceeded = limit.hard > 0 && len >= limit.hard;
+        let mut soft_limit_since = self.soft_limit_since.lock().unwrap();
+        let soft_exceeded = if limit.soft > 0 && len >= limit.soft {
+            let since = *soft_limit_since.get_or_insert_with(Instant::now);
+            since.elapsed() >= Duration::from_secs(limit.soft_seconds)
+        } else {
+            *soft_limit_since = None;
+            false
+        };
+        if (hard_exceeded || soft_exceeded) && !self.is_closing() {
+            eprintln!(
+                "Client {} scheduled to be closed ASAP for overcoming of output buffer limits.",
+                self.client
+            );
+            self.close();
+        }
+    }
+
+    /// Asks the connection to close, interrupting whatever it's doing
+    pub(crate) fn close(&self) {
+        if !self.closing.swap(true, Ordering::Relaxed) {
+            self.close.notify_one();
+        }
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the connection is asked to close
+    pub(crate) async fn closed(&self) {
+        self.close.notified().await;
+    }
+}
+
+/// Data queued for a client, counting towards its output buffer
+pub(crate) trait OutputSize {
+    fn output_size(&self) -> usize;
+}
+
+impl OutputSize for RespType {
+    fn output_size(&self) -> usize {
+        self.encoded_len()
+    }
+}
+
+impl OutputSize for Arc<[u8]> {
+    fn output_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Sender of data queued for a client, checking the client's output buffer limits
+#[derive(Debug)]
+pub(crate) struct OutputSender<T> {
+    tx: UnboundedSender<T>,
+    output: Arc<ClientOutput>,
+}
+
+impl<T> Clone for OutputSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            output: Arc::clone(&self.output),
+        }
+    }
+}
+
+impl<T: OutputSize> OutputSender<T> {
+    pub(crate) fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let size = item.output_size();
+        // Counted before sending, so the receiver never subtracts more than was added
+        self.output.queued.fetch_add(size, Ordering::Relaxed);
+        if let Err(err) = self.tx.send(item) {
+            self.output.queued.fetch_sub(size, Ordering::Relaxed);
+            return Err(err);
+        }
+        self.output.check_limits();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OutputReceiver<T> {
+    rx: UnboundedReceiver<T>,
+    output: Arc<ClientOutput>,
+}
+
+impl<T: OutputSize> OutputReceiver<T> {
+    fn received(&self, item: T) -> T {
+        self.output.queued.fetch_sub(item.output_size(), Ordering::Relaxed);
+        item
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        let item = self.rx.recv().await?;
+        Some(self.received(item))
+    }
+
+    pub(crate) fn try_recv(&mut self) -> Option<T> {
+        let item = self.rx.try_recv().ok()?;
+        Some(self.received(item))
+    }
+}
+
+/// Channel of data queued for the client owning `output`
+pub(crate) fn output_channel<T>(output: &Arc<ClientOutput>) -> (OutputSender<T>, OutputReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let sender = OutputSender {
+        tx,
+        output: Arc::clone(output),
+    };
+    let receiver = OutputReceiver {
+        rx,
+        output: Arc::clone(output),
+    };
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(limits: &str) -> Arc<ClientOutput> {
+        let mut config = OutputBufferLimits::default();
+        config.parse(limits).unwrap();
+        Arc::new(ClientOutput::new(String::from("test"), Arc::new(Mutex::new(config))))
+    }
+
+    /// Bulk string encoded in `len` bytes, for two digit payload lengths
+    fn push(len: usize) -> RespType {
+        RespType::bulk_string_from_string("x".repeat(len - 7))
+    }
+
+    #[test]
+    fn limits_are_parsed_per_class() {
+        let mut limits = OutputBufferLimits::default();
+        limits.parse("normal 1mb 512kb 10 PUBSUB 1k 0 0").unwrap();
+        assert_eq!(limits.normal, OutputBufferLimit { hard: 1024 * 1024, soft: 512 * 1024, soft_seconds: 10 });
+        assert_eq!(limits.pubsub, OutputBufferLimit { hard: 1000, soft: 0, soft_seconds: 0 });
+        assert_eq!(limits.replica, OutputBufferLimits::default().replica);
+        assert_eq!(limits.to_config_string(), "normal 1048576 524288 10 slave 268435456 67108864 60 pubsub 1000 0 0");
+
+        for invalid in ["", "normal 1 2", "nosuchclass 0 0 0", "normal 1tb 0 0", "normal 0 0 -1", "normal 0 0 0 pubsub x 0 0"] {
+            assert!(limits.parse(invalid).is_err(), "{invalid}");
+        }
+        // Nothing is changed unless all the groups are valid
+        assert_eq!(limits.pubsub.hard, 1000);
+    }
+
+    #[tokio::test]
+    async fn hard_limit_closes_at_once() {
+        let output = output("normal 100 0 0");
+        let (tx, mut rx) = output_channel(&output);
+        tx.send(push(60)).unwrap();
+        assert!(!output.is_closing());
+        tx.send(push(60)).unwrap();
+        assert!(output.is_closing());
+        assert_eq!(output.len(), 120);
+        rx.recv().await.unwrap();
+        assert_eq!(output.len(), 60);
+    }
+
+    #[tokio::test]
+    async fn soft_limit_closes_once_exceeded_long_enough() {
+        let output = output("normal 0 100 1");
+        let (tx, mut rx) = output_channel(&output);
+        tx.send(push(104)).unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        output.check_limits();
+        assert!(!output.is_closing());
+
+        // Dropping below the soft limit starts the period over
+        rx.recv().await.unwrap();
+        output.check_limits();
+        tx.send(push(104)).unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        output.check_limits();
+        assert!(!output.is_closing());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        output.check_limits();
+        assert!(output.is_closing());
+    }
+
+    #[test]
+    fn limits_follow_class_of_client() {
+        let output = output("normal 0 0 0 pubsub 100 0 0");
+        output.set_buffered(150);
+        assert!(!output.is_closing());
+        output.set_class(OutputClass::PubSub);
+        output.check_limits();
+        assert!(output.is_closing());
+    }
+
+    #[test]
+    fn peak_is_kept_until_taken() {
+        let output = output("normal 0 0 0");
+        output.set_buffered(500);
+        output.set_buffered(100);
+        assert_eq!(output.peak(), 500);
+        assert_eq!(output.take_peak(), 500);
+        assert_eq!(output.peak(), 100);
+    }
+}
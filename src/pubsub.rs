@@ -0,0 +1,135 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::glob::glob_match;
+use crate::output::OutputSender;
+use crate::resp::RespType;
+
+/// Sender of out-of-band messages (pushes) delivered to the connection
+pub(crate) type PushSender = OutputSender<RespType>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SubscriptionKind {
+    Channel,
+    Pattern,
+    /// Sharded channel, namespace separate from the classic channels
+    Shard,
+}
+
+/// Registry of pub/sub subscriptions, keyed by channel (or pattern) and client ID
+#[derive(Debug, Default)]
+pub(crate) struct PubSub {
+    channels: HashMap<Box<[u8]>, HashMap<u64, PushSender>>,
+    patterns: HashMap<Box<[u8]>, HashMap<u64, PushSender>>,
+    shard_channels: HashMap<Box<[u8]>, HashMap<u64, PushSender>>,
+}
+
+fn add(registry: &mut HashMap<Box<[u8]>, HashMap<u64, PushSender>>, name: &[u8], client_id: u64, sender: &PushSender) {
+    registry
+        .entry(name.into())
+        .or_default()
+        .insert(client_id, sender.clone());
+}
+
+fn remove(registry: &mut HashMap<Box<[u8]>, HashMap<u64, PushSender>>, name: &[u8], client_id: u64) {
+    if let Some(subscribers) = registry.get_mut(name) {
+        subscribers.remove(&client_id);
+        if subscribers.is_empty() {
+            registry.remove(name);
+        }
+    }
+}
+
+impl PubSub {
+    fn registry(&mut self, kind: SubscriptionKind) -> &mut HashMap<Box<[u8]>, HashMap<u64, PushSender>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    pub fn subscribe(&mut self, kind: SubscriptionKind, name: &[u8], client_id: u64, sender: &PushSender) {
+        add(self.registry(kind), name, client_id, sender);
+    }
+
+    pub fn unsubscribe(&mut self, kind: SubscriptionKind, name: &[u8], client_id: u64) {
+        remove(self.registry(kind), name, client_id);
+    }
+
+    pub fn is_subscribed(&self, kind: SubscriptionKind, name: &[u8], client_id: u64) -> bool {
+        let registry = match kind {
+            SubscriptionKind::Channel => &self.channels,
+            SubscriptionKind::Pattern => &self.patterns,
+            SubscriptionKind::Shard => &self.shard_channels,
+        };
+        registry.get(name).is_some_and(|subscribers| subscribers.contains_key(&client_id))
+    }
+
+    /// Delivers message to subscribers of the channel and matching patterns, returning their count
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        for sender in self.channels.get(channel).into_iter().flat_map(HashMap::values) {
+            let push = RespType::Push(VecDeque::from([
+                RespType::bulk_string_from_bytes(b"message"),
+                RespType::bulk_string_from_bytes(channel),
+                RespType::bulk_string_from_bytes(message),
+            ]));
+            // Closed connection just didn't get to unsubscribe yet
+            if sender.send(push).is_ok() {
+                receivers += 1;
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if !glob_match(pattern, channel, false) {
+                continue;
+            }
+            for sender in subscribers.values() {
+                let push = RespType::Push(VecDeque::from([
+                    RespType::bulk_string_from_bytes(b"pmessage"),
+                    RespType::BulkString(pattern.to_vec().into()),
+                    RespType::bulk_string_from_bytes(channel),
+                    RespType::bulk_string_from_bytes(message),
+                ]));
+                if sender.send(push).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Delivers message to subscribers of the sharded channel, returning their count
+    pub fn spublish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        for sender in self.shard_channels.get(channel).into_iter().flat_map(HashMap::values) {
+            let push = RespType::Push(VecDeque::from([
+                RespType::bulk_string_from_bytes(b"smessage"),
+                RespType::bulk_string_from_bytes(channel),
+                RespType::bulk_string_from_bytes(message),
+            ]));
+            if sender.send(push).is_ok() {
+                receivers += 1;
+            }
+        }
+        receivers
+    }
+
+    /// Active channels (with at least one subscriber), optionally matching the pattern
+    pub fn channels(&self, sharded: bool, pattern: Option<&[u8]>) -> Vec<Box<[u8]>> {
+        let channels = if sharded { &self.shard_channels } else { &self.channels };
+        channels
+            .keys()
+            .filter(|channel| pattern.iter().all(|p| glob_match(p, channel, false)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, sharded: bool, channel: &[u8]) -> usize {
+        let channels = if sharded { &self.shard_channels } else { &self.channels };
+        channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
@@ -1,31 +1,40 @@
 use std::iter::once;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::{bail, ensure, Context};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::resp::{RespReader, RespType, RespWriter};
-use crate::Server;
+use crate::{Connection, Server};
 
-pub struct ReplicationConnection<'a> {
-    reader: RespReader<'a>,
-    writer: RespWriter<'a>,
-    #[allow(dead_code)]
+pub struct ReplicationConnection<R, W> {
+    reader: RespReader<R>,
+    writer: RespWriter<W>,
+    addr: SocketAddr,
+    laddr: SocketAddr,
     server: Server,
 }
 
-impl<'a> ReplicationConnection<'a> {
-    pub fn new(stream: &'a mut TcpStream, server: Server) -> Self {
-        let (reader, writer) = stream.split();
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> ReplicationConnection<R, W> {
+    /// Link to our master at `addr`, `laddr` being our end of it
+    pub fn new(reader: R, writer: W, addr: SocketAddr, laddr: SocketAddr, server: Server) -> Self {
+        let mut reader = RespReader::new(reader, Arc::clone(&server.proto_limits));
+        reader.set_replies(true);
         Self {
-            reader: RespReader::new(reader),
+            reader,
             writer: RespWriter::new(writer),
+            addr,
+            laddr,
             server,
         }
     }
 
-    async fn ensure_pong(&mut self) -> anyhow::Result<()> {
+    /// Returns whether the master requires authentication
+    async fn ensure_pong(&mut self) -> anyhow::Result<bool> {
         match self.reader.read_item().await? {
-            Some(RespType::SimpleString(s)) if s == "PONG" => Ok(()),
+            Some(RespType::SimpleString(s)) if s == "PONG" => Ok(false),
+            Some(RespType::SimpleError(s)) if s.starts_with("NOAUTH") => Ok(true),
             Some(_) => bail!("Received invalid reponse for PING"),
             None => bail!("Failed to receive PONG response to our PING"),
         }
@@ -33,6 +42,7 @@ impl<'a> ReplicationConnection<'a> {
     async fn ensure_ok(&mut self) -> anyhow::Result<()> {
         match self.reader.read_item().await? {
             Some(RespType::SimpleString(s)) if s == "OK" => Ok(()),
+            Some(RespType::SimpleError(s)) => bail!("Master replied with error: {s}"),
             Some(_) => bail!("Received invalid reponse for PING"),
             None => bail!("Failed to receive PONG response to our PING"),
         }
@@ -55,13 +65,24 @@ impl<'a> ReplicationConnection<'a> {
 
     async fn handshake(&mut self) -> anyhow::Result<()> {
         eprintln!("Starting replication handshake");
-        self.writer.write_item(RespType::Array(once(RespType::BulkString(b"ping".to_vec().into_boxed_slice())).collect())).await?;
-        self.ensure_pong().await?;
+        self.writer.write_item(RespType::Array(once(RespType::bulk_string_from_bytes(b"ping")).collect())).await?;
+        let auth_required = self.ensure_pong().await?;
+        if let Some(password) = self.server.masterauth.clone() {
+            self.writer.write_item(RespType::Array(
+                // AUTH <password>
+                once(RespType::bulk_string_from_bytes(b"AUTH"))
+                .chain(once(RespType::bulk_string_from_string(password)))
+                .collect()
+            )).await?;
+            self.ensure_ok().await.context("Authentication with master failed")?;
+        } else if auth_required {
+            bail!("Master requires authentication, but `masterauth` is not configured");
+        }
         self.writer.write_item(RespType::Array(
             // REPLCONF listening-port <PORT>
             once(RespType::bulk_string_from_bytes(b"REPLCONF"))
             .chain(once(RespType::bulk_string_from_bytes(b"listening-port")))
-            .chain(once(RespType::bulk_string_from_string(format!("{}", self.server.addr.port()))))
+            .chain(once(RespType::bulk_string_from_string(format!("{}", self.server.port))))
             .collect()
         )).await?;
         self.ensure_ok().await?;
@@ -83,17 +104,19 @@ impl<'a> ReplicationConnection<'a> {
         // TODO: store it
         // FULLRESYNC <ID> <offset>
         let _master_id = self.ensure_full_resync().await?;
-        // handle RDB file...
+        // TODO: load it
+        let _rdb_file = self.reader.read_rdb_file().await?;
         eprintln!("Replication handshake done");
         Ok(())
     }
 
     pub async fn run_replication_loop(mut self) -> anyhow::Result<()> {
         self.handshake().await?;
+        // Once synchronized, the master sends us commands as any other client
+        self.reader.set_replies(false);
         eprintln!("Starting replication loop");
-        /*loop {
-            
-        }*/
+        let conn = Connection::master_link(self.reader, self.writer, self.addr.into(), self.laddr.into(), self.server);
+        conn.run_processing_loop().await?;
         eprintln!("Stopping replication loop");
         Ok(())
     }
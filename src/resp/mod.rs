@@ -2,6 +2,7 @@ mod reader;
 mod types;
 mod writer;
 
+pub(crate) use reader::ProtocolLimits;
 pub use reader::RespReader;
 pub use types::RespType;
 pub use writer::RespWriter;
@@ -1,105 +1,672 @@
 use std::collections::VecDeque;
-use std::future::Future;
-use std::io::ErrorKind;
-use std::pin::Pin;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use anyhow::{bail, Context};
-use tokio::io::{AsyncBufReadExt as _, AsyncReadExt, BufReader};
-use tokio::net::tcp::ReadHalf;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::error::ProtocolError;
 use crate::resp::RespType;
 
-pub struct RespReader<'stream> {
-    reader: BufReader<ReadHalf<'stream>>,
-    buf: Vec<u8>,
+/// Splits inline command into arguments, handling quotes and escapes the same way as `redis-cli`
+fn split_inline_args(line: &[u8]) -> Result<VecDeque<RespType>, ProtocolError> {
+    let unbalanced = || ProtocolError(String::from("unbalanced quotes in request"));
+    let mut args = VecDeque::new();
+    let mut bytes = line.iter().copied().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(first) = bytes.peek().copied() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            b'"' => {
+                bytes.next();
+                loop {
+                    match bytes.next().ok_or_else(unbalanced)? {
+                        b'"' => break,
+                        b'\\' => match bytes.next().ok_or_else(unbalanced)? {
+                            b'n' => arg.push(b'\n'),
+                            b'r' => arg.push(b'\r'),
+                            b't' => arg.push(b'\t'),
+                            b'b' => arg.push(0x08),
+                            b'a' => arg.push(0x07),
+                            b'x' if bytes.clone().take(2).filter(u8::is_ascii_hexdigit).count() == 2 => {
+                                let mut digit = || {
+                                    let digit = bytes.next().and_then(|b| char::from(b).to_digit(16));
+                                    digit.expect("Hex digits were checked") as u8
+                                };
+                                arg.push((digit() << 4) | digit());
+                            }
+                            b => arg.push(b),
+                        },
+                        b => arg.push(b),
+                    }
+                }
+            }
+            b'\'' => {
+                bytes.next();
+                loop {
+                    match bytes.next().ok_or_else(unbalanced)? {
+                        b'\'' => break,
+                        b'\\' if bytes.peek() == Some(&b'\'') => {
+                            bytes.next();
+                            arg.push(b'\'');
+                        }
+                        b => arg.push(b),
+                    }
+                }
+            }
+            _ => {
+                while let Some(b) = bytes.next_if(|b| !b.is_ascii_whitespace()) {
+                    arg.push(b);
+                }
+            }
+        }
+        // Closing quote must be followed by a space or the end of line
+        if matches!(first, b'"' | b'\'') && bytes.peek().is_some_and(|b| !b.is_ascii_whitespace()) {
+            return Err(unbalanced());
+        }
+        args.push_back(RespType::BulkString(arg.into()));
+    }
+}
+
+/// Bulk strings and arrays longer than this grow as the data arrives, instead of trusting the declared length
+const PREALLOC_MAX: usize = 16 * 1024;
+
+/// Free space ensured in the read buffer before every read from the socket
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Limits of requests accepted by `RespReader`, shared by all connections so `CONFIG SET` applies immediately
+#[derive(Debug)]
+pub(crate) struct ProtocolLimits {
+    /// `proto-max-bulk-len`, maximum length of bulk string
+    pub(crate) max_bulk_len: AtomicUsize,
+    /// Maximum number of array items
+    pub(crate) max_multibulk_len: AtomicUsize,
+    /// Maximum depth of nested arrays
+    pub(crate) max_nesting: AtomicUsize,
+    /// Maximum length of inline command or any other line
+    pub(crate) max_line_len: AtomicUsize,
+}
+
+impl ProtocolLimits {
+    fn get(limit: &AtomicUsize) -> usize {
+        limit.load(Ordering::Relaxed)
+    }
+}
+
+/// Decoded frame referring to bulk payloads by their position in the read buffer
+enum Frame {
+    Value(RespType),
+    Bulk(Range<usize>),
+    Array(Vec<Frame>),
+}
+
+impl Frame {
+    /// Converts the frame to `RespType`, slicing bulk payloads out of `data` without copying them
+    fn into_resp(self, data: &Bytes) -> RespType {
+        match self {
+            Self::Value(value) => value,
+            Self::Bulk(range) => RespType::BulkString(data.slice(range)),
+            Self::Array(items) => RespType::Array(items.into_iter().map(|item| item.into_resp(data)).collect()),
+        }
+    }
+}
+
+/// Element decoded by `Parser`, arrays are assembled from their items by `RespReader`
+enum Element {
+    Frame(Frame),
+    /// Header of an array with the given number of items
+    Array(usize),
+}
+
+/// Array of the frame being decoded, waiting for the rest of its items
+struct PartialArray {
+    items: Vec<Frame>,
+    remaining: usize,
+}
+
+/// Cursor over the buffered input, decoding a single element without consuming it
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    limits: &'a ProtocolLimits,
+    /// Decoding replies of a server, which may be of any type, instead of requests
+    replies: bool,
+    /// Offset up to which the incomplete line at the cursor was already searched for its end
+    scanned: usize,
+    /// Length the buffer needs to reach before parsing of an incomplete element may proceed
+    needed: usize,
+    /// Bytes searched for line ends
+    #[cfg(test)]
+    searched: usize,
+}
+
+type ParseResult<T> = Result<Option<T>, ProtocolError>;
+
+impl<'a> Parser<'a> {
+    fn incomplete<T>(&mut self, needed: usize) -> ParseResult<T> {
+        self.needed = needed;
+        Ok(None)
+    }
+
+    /// Returns the line at the cursor without its (CR)LF, moving the cursor behind it
+    fn line(&mut self, too_big: &str) -> ParseResult<&'a [u8]> {
+        // Limits may be configured up to `usize::MAX`
+        let max_len = ProtocolLimits::get(&self.limits.max_line_len).saturating_add(2);
+        let start = self.pos;
+        let end = self.buf.len().min(start.saturating_add(max_len));
+        let from = self.scanned.clamp(start, end);
+        let found = self.buf[from..end].iter().position(|b| *b == b'\n');
+        #[cfg(test)]
+        {
+            self.searched += found.map_or(end - from, |offset| offset + 1);
+        }
+        match found {
+            Some(offset) => {
+                self.pos = from + offset + 1;
+                let line = &self.buf[start..from + offset];
+                Ok(Some(line.strip_suffix(b"\r").unwrap_or(line)))
+            }
+            None if end - start >= max_len => Err(ProtocolError(String::from(too_big))),
+            None => {
+                self.scanned = end;
+                self.incomplete(self.buf.len() + 1)
+            }
+        }
+    }
+
+    /// Consumes the `-1` length of a null reply, which requests never contain
+    fn null(&mut self) -> bool {
+        let null = self.replies && self.buf[self.pos..].starts_with(b"-1\r\n");
+        if null {
+            self.pos += 4;
+        }
+        null
+    }
+
+    /// Reads length of bulk string or array, which must be a non-negative integer up to `max`
+    fn len(&mut self, too_big: &str, max: usize, invalid: &str) -> ParseResult<usize> {
+        let Some(line) = self.line(too_big)? else {
+            return Ok(None);
+        };
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len <= max)
+            .map(Some)
+            .ok_or_else(|| ProtocolError(String::from(invalid)))
+    }
+
+    /// Decodes element nested in `depth` arrays.
+    ///
+    /// Same as in Redis, requests are arrays of bulk strings and anything else is an inline command.
+    fn element(&mut self, depth: usize) -> ParseResult<Element> {
+        let Some(&first) = self.buf.get(self.pos) else {
+            return self.incomplete(self.buf.len() + 1);
+        };
+        match first {
+            b'*' => {
+                if depth >= ProtocolLimits::get(&self.limits.max_nesting) {
+                    return Err(ProtocolError(String::from("too deep nesting of arrays")));
+                }
+                self.pos += 1;
+                if self.null() {
+                    return Ok(Some(Element::Frame(Frame::Value(RespType::NullArray))));
+                }
+                let max_count = ProtocolLimits::get(&self.limits.max_multibulk_len);
+                let Some(count) = self.len("too big mbulk count string", max_count, "invalid multibulk length")? else {
+                    return Ok(None);
+                };
+                Ok(Some(Element::Array(count)))
+            }
+            _ if depth == 0 && !self.replies => {
+                // Inline command, e.g. typed into telnet, terminated by (CR)LF
+                let Some(line) = self.line("too big inline request")? else {
+                    return Ok(None);
+                };
+                Ok(Some(Element::Frame(Frame::Value(RespType::Array(split_inline_args(line)?)))))
+            }
+            b'$' => {
+                self.pos += 1;
+                if self.null() {
+                    return Ok(Some(Element::Frame(Frame::Value(RespType::NullBulkString))));
+                }
+                let max_len = ProtocolLimits::get(&self.limits.max_bulk_len);
+                let Some(len) = self.len("too big bulk count string", max_len, "invalid bulk length")? else {
+                    return Ok(None);
+                };
+                let start = self.pos;
+                let end = start.saturating_add(len).saturating_add(2);
+                if self.buf.len() < end {
+                    return self.incomplete(end);
+                }
+                self.pos = end;
+                if &self.buf[start + len..self.pos] != b"\r\n" {
+                    return Err(ProtocolError(String::from("expected CRLF after bulk string")));
+                }
+                Ok(Some(Element::Frame(Frame::Bulk(start..start + len))))
+            }
+            b'+' | b'-' | b':' if self.replies => {
+                self.pos += 1;
+                let Some(line) = self.line("too big line")? else {
+                    return Ok(None);
+                };
+                let line = std::str::from_utf8(line)
+                    .map_err(|_| ProtocolError(String::from("non-UTF-8 valid string provided")))?;
+                let value = match first {
+                    b'+' => RespType::SimpleString(line.to_owned()),
+                    b'-' => RespType::SimpleError(line.to_owned()),
+                    _ => RespType::Integer(line.parse().map_err(|_| ProtocolError(String::from("invalid integer")))?),
+                };
+                Ok(Some(Element::Frame(Frame::Value(value))))
+            }
+            b if self.replies => Err(ProtocolError(format!("unknown reply type '{}'", char::from(b)))),
+            b => Err(ProtocolError(format!("expected '$', got '{}'", char::from(b)))),
+        }
+    }
+}
+
+pub struct RespReader<R> {
+    reader: R,
+    buf: BytesMut,
+    limits: Arc<ProtocolLimits>,
+    /// Set while reading replies of our master during the replication handshake
+    replies: bool,
+    /// Offset of the next element of the frame being decoded, the frame stays buffered until it's complete
+    pos: usize,
+    /// Arrays of the frame being decoded, the innermost one last
+    arrays: Vec<PartialArray>,
+    /// Offset up to which the incomplete line at `pos` was already searched for its end
+    scanned: usize,
+    /// Length the buffer needs to reach before parsing the incomplete element again
+    needed: usize,
+    /// Work done by the parser, so tests can check each byte is parsed once
+    #[cfg(test)]
+    stats: ParseStats,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default, Clone, Copy)]
+struct ParseStats {
+    /// Elements decoded, counting the attempts ending on an incomplete element
+    attempts: usize,
+    /// Bytes searched for line ends
+    searched: usize,
 }
 
-impl<'stream> RespReader<'stream> {
-    pub(crate) fn new(reader: ReadHalf<'stream>) -> Self {
+impl<R: AsyncRead + Unpin> RespReader<R> {
+    pub(crate) fn new(reader: R, limits: Arc<ProtocolLimits>) -> Self {
         Self {
-            reader: BufReader::new(reader),
-            buf: Vec::new(),
+            reader,
+            buf: BytesMut::with_capacity(READ_CHUNK),
+            limits,
+            replies: false,
+            pos: 0,
+            arrays: Vec::new(),
+            scanned: 0,
+            needed: 0,
+            #[cfg(test)]
+            stats: ParseStats::default(),
         }
     }
 
-    async fn read_until_crlf(&mut self) -> std::io::Result<usize> {
-        let mut read = 0;
+    /// Switches between decoding replies of a server and requests of a client
+    pub(crate) fn set_replies(&mut self, replies: bool) {
+        self.replies = replies;
+    }
+
+    /// Reads more data from the socket, returning `false` on EOF
+    async fn fill(&mut self) -> anyhow::Result<bool> {
+        self.buf.reserve(READ_CHUNK);
+        let read = self.reader.read_buf(&mut self.buf).await.context("Error while reading data")?;
+        Ok(read > 0)
+    }
+
+    /// Size of the query buffer, i.e. received data not consumed by a complete frame yet
+    pub(crate) fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decodes a complete frame from the buffer, consuming it.
+    ///
+    /// Elements of an incomplete frame are kept decoded, so each byte is parsed once however the frame arrives.
+    fn parse(&mut self) -> anyhow::Result<Option<RespType>> {
         loop {
-            read += self.reader.read_until(b'\n', &mut self.buf).await?;
-            if read > 1
-                && self.buf[self.buf.len() - 1] == b'\n'
-                && self.buf[self.buf.len() - 2] == b'\r'
+            if self.buf.len() < self.needed {
+                return Ok(None);
+            }
+            let mut parser = Parser {
+                buf: &self.buf,
+                pos: self.pos,
+                limits: &self.limits,
+                replies: self.replies,
+                scanned: self.scanned,
+                needed: 0,
+                #[cfg(test)]
+                searched: 0,
+            };
+            let element = parser.element(self.arrays.len());
+            #[cfg(test)]
             {
-                // Remove the ending CRLF
-                self.buf.pop();
-                self.buf.pop();
-                read -= 2;
-                break;
-            }
-        }
-
-        Ok(read)
-    }
-
-    async fn read_string(&mut self) -> anyhow::Result<String> {
-        self.buf.clear();
-        self.read_until_crlf().await?;
-        Ok(std::str::from_utf8(&self.buf)
-            .context("Non-UTF-8 valid string provided")?
-            .to_string())
-    }
-
-    async fn read_i64(&mut self) -> anyhow::Result<i64> {
-        self.buf.clear();
-        self.read_until_crlf().await?;
-        std::str::from_utf8(&self.buf)
-            .context("Non-UTF-8 valid string provided")?
-            .parse()
-            .context("Failed to parse string to int")
-    }
-
-    async fn read_usize(&mut self) -> anyhow::Result<usize> {
-        self.buf.clear();
-        self.read_until_crlf().await?;
-        std::str::from_utf8(&self.buf)
-            .context("Non-UTF-8 valid string provided")?
-            .parse()
-            .context("Failed to parse string to int")
-    }
-
-    #[must_use]
-    pub fn read_item<'a>(
-        &'a mut self,
-    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<RespType>>> + 'a + Send>> {
-        Box::pin(async move {
-            match self.reader.read_u8().await {
-                Ok(b'+') => Ok(Some(RespType::SimpleString(self.read_string().await?))),
-                Ok(b'-') => Ok(Some(RespType::SimpleError(self.read_string().await?))),
-                Ok(b':') => Ok(Some(RespType::Integer(self.read_i64().await?))),
-                Ok(b'$') => {
-                    let len = self.read_usize().await?;
-                    let mut buf = vec![0; len + 2];
-                    self.reader.read_exact(&mut buf).await?;
-                    assert_eq!(Some(b'\n'), buf.pop());
-                    assert_eq!(Some(b'\r'), buf.pop());
-                    Ok(Some(RespType::BulkString(buf.into_boxed_slice())))
+                self.stats.attempts += 1;
+                self.stats.searched += parser.searched;
+            }
+            let element = element?;
+            let (pos, scanned, needed) = (parser.pos, parser.scanned, parser.needed);
+            let Some(element) = element else {
+                self.scanned = scanned;
+                self.needed = needed;
+                return Ok(None);
+            };
+            self.pos = pos;
+            self.scanned = 0;
+            self.needed = 0;
+
+            let mut frame = match element {
+                Element::Frame(frame) => frame,
+                Element::Array(0) => Frame::Array(Vec::new()),
+                Element::Array(count) => {
+                    let items = Vec::with_capacity(count.min(PREALLOC_MAX));
+                    self.arrays.push(PartialArray { items, remaining: count });
+                    continue;
                 }
-                Ok(b'*') => {
-                    let count = self.read_usize().await?;
-                    let mut arr = VecDeque::with_capacity(count);
-                    for _ in 0..count {
-                        arr.push_back(
-                            self.read_item()
-                                .await?
-                                .ok_or(anyhow::anyhow!("Missing array item"))?,
-                        );
-                    }
-                    Ok(Some(RespType::Array(arr)))
+            };
+            // The element completes its array once it's the last item, which may complete the outer array too
+            loop {
+                let Some(array) = self.arrays.last_mut() else {
+                    let data = self.buf.split_to(std::mem::take(&mut self.pos)).freeze();
+                    return Ok(Some(frame.into_resp(&data)));
+                };
+                array.items.push(frame);
+                array.remaining -= 1;
+                if array.remaining > 0 {
+                    break;
+                }
+                frame = Frame::Array(self.arrays.pop().expect("Array is on the stack").items);
+            }
+        }
+    }
+
+    /// Reads RDB file sent by master, which is encoded as bulk string without the trailing CRLF
+    pub async fn read_rdb_file(&mut self) -> anyhow::Result<Box<[u8]>> {
+        loop {
+            if let Some(&first) = self.buf.first() {
+                if first != b'$' {
+                    bail!("Unexpected first byte {} of RDB file", first);
                 }
-                Ok(b) => bail!("Unrecognized first byte {}", b),
-                Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
-                Err(err) => Err(err).context("Error while reading data"),
+                let mut parser = Parser {
+                    buf: &self.buf,
+                    pos: 1,
+                    limits: &self.limits,
+                    replies: true,
+                    scanned: 0,
+                    needed: 0,
+                    #[cfg(test)]
+                    searched: 0,
+                };
+                let len = parser.len("too big bulk count string", usize::MAX, "invalid length of RDB file")?;
+                if let Some(len) = len.filter(|len| self.buf.len() >= parser.pos + len) {
+                    self.buf.advance(parser.pos);
+                    return Ok(self.buf.split_to(len).to_vec().into_boxed_slice());
+                }
+            }
+            if !self.fill().await? {
+                bail!("Connection closed while reading RDB file");
+            }
+        }
+    }
+
+    /// Returns the next item if it's already buffered, without reading from the socket
+    pub(crate) fn try_read_item(&mut self) -> anyhow::Result<Option<RespType>> {
+        loop {
+            match self.parse()? {
+                // Empty lines are ignored
+                Some(RespType::Array(items)) if items.is_empty() => continue,
+                item => return Ok(item),
             }
+        }
+    }
+
+    /// Reads the next item, `None` once the connection is closed. Cancelling the read loses no data.
+    pub async fn read_item(&mut self) -> anyhow::Result<Option<RespType>> {
+        loop {
+            if let Some(item) = self.try_read_item()? {
+                return Ok(Some(item));
+            }
+            if !self.fill().await? {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                bail!("Connection closed in the middle of a request");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    fn limits() -> Arc<ProtocolLimits> {
+        Arc::new(ProtocolLimits {
+            max_bulk_len: AtomicUsize::new(64),
+            max_multibulk_len: AtomicUsize::new(8),
+            max_nesting: AtomicUsize::new(2),
+            max_line_len: AtomicUsize::new(32),
         })
     }
+
+    /// Input delivered in chunks of the given size, so frames arrive split across reads
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl AsyncRead for Chunked<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            let len = self.chunk.min(self.data.len()).min(buf.remaining());
+            let (chunk, rest) = self.data.split_at(len);
+            buf.put_slice(chunk);
+            self.data = rest;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Reads all requests from the input, returning them as lists of arguments
+    async fn read_all(input: &[u8], chunk: usize) -> anyhow::Result<Vec<Vec<Bytes>>> {
+        let mut reader = RespReader::new(Chunked { data: input, chunk }, limits());
+        let mut requests = Vec::new();
+        while let Some(item) = reader.read_item().await? {
+            let RespType::Array(args) = item else {
+                panic!("Request must be an array, got {item:?}");
+            };
+            let args = args.into_iter().map(|arg| match arg {
+                RespType::BulkString(arg) => arg,
+                arg => panic!("Argument must be a bulk string, got {arg:?}"),
+            });
+            requests.push(args.collect());
+        }
+        Ok(requests)
+    }
+
+    /// Asserts the input is rejected with a protocol error, which is replied as `-ERR Protocol error: <message>`
+    async fn assert_protocol_error(input: &[u8], message: &str) {
+        for chunk in [1, 3, input.len().max(1)] {
+            let err = read_all(input, chunk).await.expect_err("Input must be rejected");
+            let err = err.downcast::<ProtocolError>().expect("Must be a protocol error");
+            assert_eq!(err.to_string(), format!("ERR Protocol error: {message}"), "input {input:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_multibulk_and_inline_requests() {
+        let input = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nSET k 'a b'\r\n\r\n*1\r\n$0\r\n\r\n";
+        for chunk in 1..=input.len() {
+            let requests = read_all(input, chunk).await.unwrap();
+            assert_eq!(requests, [vec!["GET", "k"], vec!["SET", "k", "a b"], vec![""]]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_frames() {
+        for input in [&b"*2\r\n$3\r\nGET\r\n"[..], b"*1\r\n$3\r\nGE", b"*1\r\n$3", b"*1", b"PING"] {
+            let err = read_all(input, input.len()).await.expect_err("Truncated input must be rejected");
+            assert!(err.to_string().contains("closed in the middle"), "input {input:?}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_lengths() {
+        assert_protocol_error(b"*x\r\n", "invalid multibulk length").await;
+        assert_protocol_error(b"*1\r\n$x\r\n", "invalid bulk length").await;
+        assert_protocol_error(b"*1\r\n$\r\n", "invalid bulk length").await;
+        assert_protocol_error(b"*1\r\n$3\r\nGETX\r\n", "expected CRLF after bulk string").await;
+        assert_protocol_error(b"*1\r\n:1\r\n", "expected '$', got ':'").await;
+        assert_protocol_error(b"*1\r\nPING\r\n", "expected '$', got 'P'").await;
+    }
+
+    #[tokio::test]
+    async fn rejects_negative_and_overflowing_sizes() {
+        assert_protocol_error(b"*-1\r\n", "invalid multibulk length").await;
+        assert_protocol_error(b"*1\r\n$-5\r\n", "invalid bulk length").await;
+        assert_protocol_error(b"*18446744073709551616\r\n", "invalid multibulk length").await;
+        assert_protocol_error(b"*1\r\n$18446744073709551615\r\n", "invalid bulk length").await;
+    }
+
+    #[tokio::test]
+    async fn enforces_limits() {
+        assert_protocol_error(b"*1\r\n$65\r\n", "invalid bulk length").await;
+        assert_protocol_error(b"*9\r\n", "invalid multibulk length").await;
+        assert_protocol_error(b"*1\r\n*1\r\n*1\r\n$1\r\na\r\n", "too deep nesting of arrays").await;
+        let long_inline = [b'a'; 40];
+        assert_protocol_error(&long_inline, "too big inline request").await;
+        assert_protocol_error(b"*1\r\n$0000000000000000000000000000000000000001\r\n", "too big bulk count string").await;
+
+        let max_bulk = format!("*1\r\n$64\r\n{}\r\n", "a".repeat(64));
+        assert_eq!(read_all(max_bulk.as_bytes(), 7).await.unwrap()[0][0].len(), 64);
+    }
+
+    #[tokio::test]
+    async fn unbounded_limits_dont_overflow() {
+        let limits = limits();
+        for limit in [&limits.max_bulk_len, &limits.max_multibulk_len, &limits.max_line_len] {
+            limit.store(usize::MAX, Ordering::Relaxed);
+        }
+        let mut reader = RespReader::new(Chunked { data: b"*1\r\n$3\r\nfoo\r\nPING\r\n", chunk: 3 }, Arc::clone(&limits));
+        assert!(matches!(reader.read_item().await.unwrap(), Some(RespType::Array(items)) if items.len() == 1));
+        assert!(matches!(reader.read_item().await.unwrap(), Some(RespType::Array(items)) if items.len() == 1));
+
+        let huge = format!("*1\r\n${}\r\nfoo", usize::MAX);
+        let mut reader = RespReader::new(Chunked { data: huge.as_bytes(), chunk: 5 }, limits);
+        let err = reader.read_item().await.unwrap_err();
+        assert_eq!(err.to_string(), "Connection closed in the middle of a request");
+    }
+
+    fn multibulk(args: usize) -> Vec<u8> {
+        let mut input = format!("*{args}\r\n").into_bytes();
+        for i in 0..args {
+            input.extend_from_slice(format!("$6\r\n{i:06}\r\n").as_bytes());
+        }
+        input
+    }
+
+    /// Reads a frame from `input` arriving `chunk` bytes at a time, parsing it from its start after every read
+    /// when `restart` is set, as the reader did before keeping incomplete frames decoded
+    async fn read_chunked(input: &[u8], chunk: usize, limits: Arc<ProtocolLimits>, restart: bool) -> (RespType, ParseStats) {
+        let mut reader = RespReader::new(Chunked { data: input, chunk }, limits);
+        loop {
+            if let Some(item) = reader.try_read_item().unwrap() {
+                return (item, reader.stats);
+            }
+            assert!(reader.fill().await.unwrap(), "Input is an incomplete frame");
+            if restart {
+                reader.pos = 0;
+                reader.arrays.clear();
+                reader.scanned = 0;
+                reader.needed = 0;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fragmented_frame_is_parsed_in_linear_time() {
+        let args = 100_000;
+        let input = multibulk(args);
+        let limits = limits();
+        limits.max_multibulk_len.store(args, Ordering::Relaxed);
+        let (item, stats) = read_chunked(&input, 64, limits, false).await;
+        assert!(matches!(item, RespType::Array(items) if items.len() == args));
+        // Each element is decoded once, plus an attempt ending on the incomplete one after each read
+        let reads = input.len().div_ceil(64);
+        assert!(stats.attempts <= args + 1 + reads, "{} attempts for {reads} reads", stats.attempts);
+        assert!(stats.searched <= input.len(), "{} bytes searched", stats.searched);
+    }
+
+    #[tokio::test]
+    async fn long_line_is_searched_once() {
+        let input = format!("SET key {}\r\n", "x".repeat(10_000)).into_bytes();
+        let limits = limits();
+        limits.max_line_len.store(input.len(), Ordering::Relaxed);
+        let (item, stats) = read_chunked(&input, 1, limits, false).await;
+        assert!(matches!(item, RespType::Array(items) if items.len() == 3));
+        assert!(stats.searched <= input.len(), "{} bytes searched", stats.searched);
+    }
+
+    /// Compares the reader with one decoding the frame from its start after every read, run with
+    /// `cargo test --release -- --ignored --nocapture fragmented_frame_bench`
+    #[tokio::test]
+    #[ignore]
+    async fn fragmented_frame_bench() {
+        for args in [1_000, 10_000, 30_000] {
+            let input = multibulk(args);
+            let limits = limits();
+            limits.max_multibulk_len.store(args, Ordering::Relaxed);
+            for (chunk, restart) in [(input.len(), false), (64, false), (64, true)] {
+                let start = std::time::Instant::now();
+                let (_, stats) = read_chunked(&input, chunk, Arc::clone(&limits), restart).await;
+                let path = if restart { "restarting" } else { "resuming" };
+                println!(
+                    "{args} args, {path} reads of {chunk} bytes: {:?}, {} attempts, {} bytes searched",
+                    start.elapsed(),
+                    stats.attempts,
+                    stats.searched
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn random_input_never_panics() {
+        // Fragments of valid frames make it more likely to get past the first byte
+        const FRAGMENTS: &[&[u8]] = &[b"*", b"$", b":", b"+", b"-", b"\r\n", b"\n", b"\r", b"0", b"1", b"3", b"-1", b"9999", b"a", b" ", b"\"", b"'", b"\\x"];
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: usize| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound as u64) as usize
+        };
+        for _ in 0..2000 {
+            let mut input = Vec::new();
+            for _ in 0..next(40) {
+                if next(4) == 0 {
+                    input.push(next(256) as u8);
+                } else {
+                    input.extend_from_slice(FRAGMENTS[next(FRAGMENTS.len())]);
+                }
+            }
+            // Either parses or fails with an error, panics fail the test
+            let mut reader = RespReader::new(Chunked { data: &input, chunk: next(8) + 1 }, limits());
+            while let Ok(Some(_)) = reader.read_item().await {}
+        }
+    }
 }
@@ -1,16 +1,22 @@
 use std::collections::VecDeque;
+use std::io::Write;
 
 use anyhow::Context;
+use bytes::Bytes;
 
-/// RESP2 types
-#[derive(Debug)]
+/// RESP2 types, plus RESP3 aggregates downgraded to arrays for RESP2 clients
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RespType {
     SimpleString(String),
     SimpleError(String),
     Integer(i64),
-    BulkString(Box<[u8]>),
+    BulkString(Bytes),
     NullBulkString,
     Array(VecDeque<Self>),
+    NullArray,
+    Map(Vec<(Self, Self)>),
+    /// Out-of-band message, e.g. pub/sub message
+    Push(VecDeque<Self>),
 }
 
 impl RespType {
@@ -20,21 +26,82 @@ impl RespType {
             Self::SimpleError(_) => b'-',
             Self::Integer(_) => b':',
             Self::BulkString(_) | Self::NullBulkString => b'$',
-            Self::Array(_) => b'*',
+            Self::Array(_) | Self::NullArray => b'*',
+            Self::Map(_) => b'%',
+            Self::Push(_) => b'>',
+        }
+    }
+
+    /// Converts RESP3 aggregate types to arrays, which is how RESP2 represents them
+    pub(crate) fn into_resp2(self) -> Self {
+        match self {
+            Self::Map(entries) => Self::Array(entries.into_iter().flat_map(|(k, v)| [k, v]).collect()),
+            Self::Push(items) => Self::Array(items),
+            item => item,
+        }
+    }
+
+    /// Appends wire representation of the item to `buf`
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        if let Self::Map(_) | Self::Push(_) = self {
+            return self.clone().into_resp2().encode(buf);
+        }
+        buf.push(self.first_byte());
+        match self {
+            Self::SimpleString(s) | Self::SimpleError(s) => buf.extend_from_slice(s.as_bytes()),
+            Self::Integer(i) => write!(buf, "{i}").expect("Writing to Vec cannot fail"),
+            Self::BulkString(data) => {
+                write!(buf, "{}\r\n", data.len()).expect("Writing to Vec cannot fail");
+                buf.extend_from_slice(data);
+            }
+            Self::NullBulkString | Self::NullArray => buf.extend_from_slice(b"-1"),
+            Self::Array(items) => {
+                write!(buf, "{}\r\n", items.len()).expect("Writing to Vec cannot fail");
+                for item in items {
+                    item.encode(buf);
+                }
+                return;
+            }
+            Self::Map(_) | Self::Push(_) => unreachable!("Converted to RESP2 above"),
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Length of the RESP2 wire representation written by `encode`
+    pub(crate) fn encoded_len(&self) -> usize {
+        let digits = |n: usize| n.checked_ilog10().unwrap_or(0) as usize + 1;
+        match self {
+            Self::SimpleString(s) | Self::SimpleError(s) => 1 + s.len() + 2,
+            Self::Integer(i) => 1 + digits(i.unsigned_abs() as usize) + usize::from(*i < 0) + 2,
+            Self::BulkString(data) => 1 + digits(data.len()) + 2 + data.len() + 2,
+            Self::NullBulkString | Self::NullArray => 5,
+            Self::Array(items) | Self::Push(items) => 1 + digits(items.len()) + 2 + items.iter().map(Self::encoded_len).sum::<usize>(),
+            Self::Map(entries) => {
+                let items: usize = entries.iter().map(|(key, value)| key.encoded_len() + value.encoded_len()).sum();
+                1 + digits(entries.len() * 2) + 2 + items
+            }
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn as_str_bytes(&self) -> anyhow::Result<&[u8]> {
         match self {
             Self::SimpleString(s) => Ok(s.as_bytes()),
             Self::BulkString(s) => Ok(s.as_ref()),
-            Self::SimpleError(_) | Self::Integer(_) | Self::NullBulkString | Self::Array(_) => {
+            Self::SimpleError(_) | Self::Integer(_) | Self::NullBulkString | Self::Array(_) | Self::NullArray | Self::Map(_) | Self::Push(_) => {
                 anyhow::bail!("Value is not a string type")
             }
         }
     }
 
+    pub(crate) fn into_bulk_string(self) -> anyhow::Result<Bytes> {
+        match self {
+            Self::BulkString(s) => Ok(s),
+            Self::SimpleString(_) | Self::SimpleError(_) | Self::Integer(_) | Self::NullBulkString | Self::Array(_) | Self::NullArray | Self::Map(_) | Self::Push(_) => {
+                anyhow::bail!("Value is not a bulk string")
+            }
+        }
+    }
+
     pub(crate) fn as_int(&self) -> anyhow::Result<i64> {
         match self {
             Self::Integer(i) => Ok(*i),
@@ -42,32 +109,19 @@ impl RespType {
             Self::BulkString(s) => std::str::from_utf8(s)?.parse().context("Failed to parse str as int"),
             Self::SimpleError(_)
             | Self::NullBulkString
-            | Self::Array(_) => {
-                anyhow::bail!("Value is not a string type")
-            }
-        }
-    }
-
-    pub(crate) fn make_str_bytes_lowercase(&mut self) -> anyhow::Result<&[u8]> {
-        match self {
-            Self::SimpleString(s) => {
-                s.make_ascii_lowercase();
-                Ok(s.as_bytes())
-            }
-            Self::BulkString(s) => {
-                s.make_ascii_lowercase();
-                Ok(s.as_mut())
-            }
-            Self::SimpleError(_) | Self::Integer(_) | Self::NullBulkString | Self::Array(_) => {
+            | Self::Array(_)
+            | Self::NullArray
+            | Self::Map(_)
+            | Self::Push(_) => {
                 anyhow::bail!("Value is not a string type")
             }
         }
     }
 
     pub(crate) fn bulk_string_from_bytes(bytes: &[u8]) -> Self {
-        RespType::BulkString(bytes.to_vec().into_boxed_slice())
+        RespType::BulkString(Bytes::copy_from_slice(bytes))
     }
     pub(crate) fn bulk_string_from_string(s: String) -> Self {
-        RespType::BulkString(s.into_bytes().into_boxed_slice())
+        RespType::BulkString(s.into())
     }
 }
@@ -1,76 +1,118 @@
-use std::future::Future;
-use std::pin::Pin;
+use std::io::Write;
 
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::tcp::WriteHalf;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::resp::RespType;
 
-pub struct RespWriter<'stream> {
-    writer: BufWriter<WriteHalf<'stream>>,
+pub struct RespWriter<W> {
+    writer: W,
+    /// Encoded replies waiting to be written to the socket
+    buf: Vec<u8>,
+    /// Whether the client switched to RESP3 by `HELLO 3`
+    resp3: bool,
 }
 
-impl<'stream> RespWriter<'stream> {
-    pub(crate) fn new(writer: WriteHalf<'stream>) -> Self {
+impl<W: AsyncWrite + Unpin> RespWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
         Self {
-            writer: BufWriter::new(writer),
+            writer,
+            buf: Vec::new(),
+            resp3: false,
         }
     }
 
-    async fn write_crlf(&mut self) -> anyhow::Result<()> {
-        self.writer.write_u8(b'\r').await?;
-        self.writer.write_u8(b'\n').await?;
-        Ok(())
+    pub(crate) fn set_resp3(&mut self, resp3: bool) {
+        self.resp3 = resp3;
     }
 
-    async fn write_len(&mut self, len: usize) -> anyhow::Result<()> {
-        self.writer.write_all(format!("{}", len).as_bytes()).await?;
-        self.write_crlf().await?;
-        Ok(())
+    /// Size of the output buffer, i.e. replies not written to the socket yet
+    pub(crate) fn buffered_len(&self) -> usize {
+        self.buf.len()
     }
 
-    pub async fn write_rdb_file(&mut self, file: &[u8]) -> anyhow::Result<()> {
-        self.writer.write_u8(b'$').await?;
-        self.write_len(file.len()).await?;
-        self.writer.write_all(file).await?;
+    /// Writes the whole output buffer to the socket
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if !self.buf.is_empty() {
+            self.writer.write_all(&self.buf).await?;
+            self.buf.clear();
+        }
         self.writer.flush().await?;
         Ok(())
     }
 
-    pub fn write_item<'a>(
-        &'a mut self,
-        item: RespType,
-    ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + 'a + Send>> {
-        Box::pin(async move {
-            self.writer.write_u8(item.first_byte()).await?;
-            match item {
-                RespType::SimpleString(s) | RespType::SimpleError(s) => {
-                    self.writer.write_all(s.as_bytes()).await?;
-                    self.write_crlf().await?;
-                }
-                RespType::Integer(i) => {
-                    self.writer.write_all(format!("{i}").as_bytes()).await?;
-                    self.write_crlf().await?;
-                }
-                RespType::BulkString(data) => {
-                    self.write_len(data.len()).await?;
-                    self.writer.write_all(&data).await?;
-                    self.write_crlf().await?;
-                }
-                RespType::NullBulkString => {
-                    self.writer.write_u8(b'-').await?;
-                    self.writer.write_u8(b'1').await?;
-                    self.write_crlf().await?;
+    pub async fn write_rdb_file(&mut self, file: &[u8]) -> anyhow::Result<()> {
+        write!(self.buf, "${}\r\n", file.len()).expect("Writing to Vec cannot fail");
+        self.buf.extend_from_slice(file);
+        self.flush().await
+    }
+
+    /// Writes already encoded data
+    pub async fn write_raw(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.buf.extend_from_slice(data);
+        self.flush().await
+    }
+
+    /// Encodes the item into the output buffer without writing it to the socket
+    pub(crate) fn buffer_item(&mut self, item: RespType) {
+        let item = if self.resp3 { item } else { item.into_resp2() };
+        let first_byte = char::from(item.first_byte());
+        match item {
+            RespType::NullBulkString | RespType::NullArray if self.resp3 => self.buf.extend_from_slice(b"_\r\n"),
+            // Nested items are encoded one by one, as RESP3 aggregates may be nested in them
+            RespType::Array(items) | RespType::Push(items) => {
+                write!(self.buf, "{first_byte}{}\r\n", items.len()).expect("Writing to Vec cannot fail");
+                for item in items {
+                    self.buffer_item(item);
                 }
-                RespType::Array(items) => {
-                    self.write_len(items.len()).await?;
-                    for item in items {
-                        self.write_item(item).await?;
-                    }
+            }
+            RespType::Map(entries) => {
+                write!(self.buf, "{first_byte}{}\r\n", entries.len()).expect("Writing to Vec cannot fail");
+                for (key, value) in entries {
+                    self.buffer_item(key);
+                    self.buffer_item(value);
                 }
             }
-            self.writer.flush().await?;
-            Ok(())
-        })
+            item => item.encode(&mut self.buf),
+        }
+    }
+
+    /// Writes the item and flushes the output buffer
+    pub async fn write_item(&mut self, item: RespType) -> anyhow::Result<()> {
+        self.buffer_item(item);
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::testing::CountingWriter;
+
+    #[tokio::test]
+    async fn buffered_items_are_written_at_once() {
+        let (counting, writes) = CountingWriter::new(Vec::new());
+        let mut writer = RespWriter::new(counting);
+        for _ in 0..1000 {
+            writer.buffer_item(RespType::SimpleString(String::from("OK")));
+        }
+        assert_eq!(writer.buffered_len(), 5000);
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+        writer.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(writer.buffered_len(), 0);
+        assert_eq!(writer.writer.inner, b"+OK\r\n".repeat(1000));
+    }
+
+    #[tokio::test]
+    async fn nested_array_is_written_at_once() {
+        let (counting, writes) = CountingWriter::new(Vec::new());
+        let mut writer = RespWriter::new(counting);
+        let inner = RespType::Array(VecDeque::from([RespType::Integer(1), RespType::bulk_string_from_bytes(b"a")]));
+        writer.write_item(RespType::Array(VecDeque::from([inner.clone(), inner]))).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(writer.writer.inner, b"*2\r\n*2\r\n:1\r\n$1\r\na\r\n*2\r\n:1\r\n$1\r\na\r\n");
     }
 }
@@ -1,25 +1,128 @@
-use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
 
-use crate::data::Data;
+use crate::acl::Acl;
+use crate::client::{ClientFilter, ClientInfo};
+use crate::data::{Data, ExpireCycleResult};
+use crate::net::TCP_KEEPALIVE_SUPPORTED;
+use crate::notify::{self, Notifier};
+use crate::output::{output_channel, ClientOutput, OutputBufferLimits, OutputReceiver, OutputSender};
+use crate::pubsub::{PubSub, PushSender, SubscriptionKind};
+use crate::resp::{ProtocolLimits, RespType};
+use crate::tracking::Tracking;
+use crate::{BindAddr, Config};
+
+/// How often the active expire cycle runs (Redis default `hz 10`)
+const ACTIVE_EXPIRE_CYCLE_PERIOD: Duration = Duration::from_millis(100);
+/// Share of the period (in percent) the active expire cycle may spend holding the data lock
+const ACTIVE_EXPIRE_CYCLE_BUDGET: u32 = 25;
+/// How often output buffers of the clients are checked and their peaks sampled
+const CLIENTS_CRON_PERIOD: Duration = Duration::from_secs(1);
+/// Number of periods `client_recent_max_output_buffer` looks back
+const OUTPUT_PEAK_PERIODS: usize = 5;
+
+const ACLFILE_NOT_CONFIGURED: &str = "This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.";
+
+/// Limits of `RespReader` changeable by `CONFIG SET`
+const PROTO_LIMITS: [&str; 4] = ["proto-max-bulk-len", "proto-max-multibulk-len", "proto-max-nesting", "proto-max-line-len"];
 
 #[derive(Debug)]
 pub enum ReplicationMode {
     Master,
-    Slave { addr: SocketAddr },
+    /// Host of our master is kept as configured, it's resolved on every connection attempt
+    Slave { host: String, port: u16 },
+}
+
+/// Write commands propagated to the connected replicas
+#[derive(Debug, Default)]
+struct ReplicationStream {
+    replicas: Vec<OutputSender<Arc<[u8]>>>,
+    /// Database selected in the stream, `None` forces `SELECT` before the next command
+    db: Option<usize>,
+}
+
+/// Connected client, reachable from other connections
+#[derive(Debug)]
+pub(crate) struct ClientHandle {
+    pub push: PushSender,
+    pub resp3: bool,
+    pub output: Arc<ClientOutput>,
+    /// State of the connection as of its last command
+    pub info: ClientInfo,
+}
+
+/// Place of an accepted connection within `maxclients`, freed when the connection is dropped
+#[derive(Debug)]
+pub struct ClientSlot(Server);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.client_slots.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Set by `CLIENT PAUSE`, suspends processing of commands from normal clients
+#[derive(Debug, Clone, Copy)]
+struct ClientPause {
+    until: Instant,
+    /// Whether all commands are paused, otherwise only the writes
+    all: bool,
 }
 
 #[derive(Debug)]
 pub struct Inner {
     pub replication: ReplicationMode,
-    pub addr: SocketAddr,
+    /// TCP port listened on all the `bind` addresses, 0 when TCP is disabled
+    pub port: u16,
+    pub bind: Vec<BindAddr>,
+    protected_mode: AtomicBool,
+    pub unixsocket: Option<PathBuf>,
+    pub unixsocketperm: Option<u32>,
+    pub databases: usize,
+    /// Password used to authenticate with our master
+    pub(crate) masterauth: Option<String>,
+    requirepass: std::sync::Mutex<Option<String>>,
+    acl: std::sync::Mutex<Acl>,
+    /// File with users loaded by `ACL LOAD` and written by `ACL SAVE`
+    aclfile: Option<PathBuf>,
+    pub(crate) proto_limits: Arc<ProtocolLimits>,
+    output_limits: Arc<std::sync::Mutex<OutputBufferLimits>>,
+    maxclients: AtomicUsize,
+    /// Accepted connections holding a `ClientSlot`
+    client_slots: AtomicUsize,
+    /// Connections rejected because of `maxclients`
+    rejected_connections: AtomicU64,
+    /// `timeout` in seconds
+    timeout: AtomicU64,
+    /// `tcp-keepalive` in seconds
+    tcp_keepalive: AtomicU64,
     pub(crate) master_replid: String,
-    pub(crate) master_repl_offset: usize,
-    data: Mutex<Data>,
+    master_repl_offset: AtomicUsize,
+    replication_stream: std::sync::Mutex<ReplicationStream>,
+    data: Mutex<Vec<Data>>,
+    pubsub: Arc<std::sync::Mutex<PubSub>>,
+    notifier: Arc<Notifier>,
+    tracking: Arc<std::sync::Mutex<Tracking>>,
+    clients: std::sync::Mutex<HashMap<u64, ClientHandle>>,
+    /// Largest client output in each of the last `OUTPUT_PEAK_PERIODS` clients cron periods
+    recent_output_peaks: std::sync::Mutex<VecDeque<usize>>,
+    next_client_id: AtomicU64,
+    client_pause: std::sync::Mutex<Option<ClientPause>>,
+    /// Wakes paused clients on `CLIENT UNPAUSE`
+    client_unpause: tokio::sync::Notify,
+    /// Running average of expired keys among sampled ones, stored as `f64` bits
+    expired_stale_perc: AtomicU64,
 }
 
 #[derive(Debug)]
@@ -40,28 +143,560 @@ impl Clone for Server {
 }
 
 impl Server {
-    pub fn new(replication: ReplicationMode, addr: SocketAddr) -> Self {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
         let master_replid = String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb");
-        let master_repl_offset = 0;
+        let master_repl_offset = AtomicUsize::new(0);
+        let pubsub = Arc::new(std::sync::Mutex::new(PubSub::default()));
+        let notify_flags = notify::parse_flags(&config.notify_keyspace_events)
+            .context("Invalid value for notify-keyspace-events")?;
+        let notifier = Arc::new(Notifier::new(Arc::clone(&pubsub), notify_flags));
+        let mut acl = Acl::default();
+        if let Some(password) = &config.requirepass {
+            acl.set_user("default", &[String::from("resetpass"), format!(">{password}")])
+                .map_err(anyhow::Error::msg)?;
+        }
+        if let Some(path) = &config.aclfile {
+            let contents = std::fs::read_to_string(path).context("Failed to read ACL file")?;
+            acl.load(&contents, &path.to_string_lossy()).map_err(anyhow::Error::msg)?;
+        }
+        let tracking = Arc::new(std::sync::Mutex::new(Tracking::new(config.tracking_table_max_keys)));
+        let proto_limits = Arc::new(ProtocolLimits {
+            max_bulk_len: AtomicUsize::new(config.proto_max_bulk_len),
+            max_multibulk_len: AtomicUsize::new(config.proto_max_multibulk_len),
+            max_nesting: AtomicUsize::new(config.proto_max_nesting),
+            max_line_len: AtomicUsize::new(config.proto_max_line_len),
+        });
+        let data = (0..config.databases)
+            .map(|id| Data::new(id, Arc::clone(&notifier), Arc::clone(&tracking)))
+            .collect();
 
-        Self(Arc::new(Inner {
-            replication,
-            addr,
+        Ok(Self(Arc::new(Inner {
+            replication: config.replication,
+            port: config.port,
+            bind: config.bind,
+            protected_mode: AtomicBool::new(config.protected_mode),
+            unixsocket: config.unixsocket,
+            unixsocketperm: config.unixsocketperm,
+            databases: config.databases,
+            masterauth: config.masterauth,
+            requirepass: std::sync::Mutex::new(config.requirepass),
+            acl: std::sync::Mutex::new(acl),
+            aclfile: config.aclfile,
+            proto_limits,
+            output_limits: Arc::new(std::sync::Mutex::new(config.client_output_buffer_limit)),
+            maxclients: AtomicUsize::new(config.maxclients),
+            client_slots: AtomicUsize::new(0),
+            rejected_connections: AtomicU64::new(0),
+            timeout: AtomicU64::new(config.timeout),
+            tcp_keepalive: AtomicU64::new(config.tcp_keepalive),
             master_replid,
             master_repl_offset,
-            data: Default::default(),
+            replication_stream: Default::default(),
+            data: Mutex::new(data),
+            pubsub,
+            notifier,
+            tracking,
+            clients: Default::default(),
+            recent_output_peaks: Default::default(),
+            next_client_id: AtomicU64::new(1),
+            client_pause: std::sync::Mutex::new(None),
+            client_unpause: tokio::sync::Notify::new(),
+            expired_stale_perc: AtomicU64::new(0f64.to_bits()),
+        })))
+    }
+
+    /// Spawns background task periodically removing expired keys nobody reads anymore.
+    pub fn spawn_active_expire(&self) -> JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ACTIVE_EXPIRE_CYCLE_PERIOD);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // Database to start with, so all of them get their turn when the time budget runs out
+            let mut next_db = 0;
+            loop {
+                interval.tick().await;
+                // Replicas wait for the `DEL` of their master, so they keep the same keys
+                if matches!(server.replication, ReplicationMode::Slave { .. }) {
+                    continue;
+                }
+                let deadline = Instant::now() + ACTIVE_EXPIRE_CYCLE_PERIOD * ACTIVE_EXPIRE_CYCLE_BUDGET / 100;
+                let mut total = ExpireCycleResult::default();
+                let mut dbs = server.0.data.lock().await;
+                for _ in 0..dbs.len() {
+                    let result = dbs[next_db].active_expire_cycle(deadline);
+                    next_db = (next_db + 1) % dbs.len();
+                    total.sampled += result.sampled;
+                    total.expired += result.expired;
+                    if result.timed_out {
+                        break;
+                    }
+                }
+                drop(dbs);
+                server.send_invalidations(None);
+
+                let current_perc = if total.sampled > 0 {
+                    total.expired as f64 / total.sampled as f64
+                } else {
+                    0f64
+                };
+                let stale_perc = current_perc * 0.05 + server.expired_stale_perc() * 0.95;
+                server.0.expired_stale_perc.store(stale_perc.to_bits(), Ordering::Relaxed);
+            }
+        })
+    }
+
+    /// Spawns background task periodically closing clients whose output stays above the soft limit, and
+    /// sampling the largest output for `client_recent_max_output_buffer`.
+    pub fn spawn_clients_cron(&self) -> JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLIENTS_CRON_PERIOD);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let mut peak = 0;
+                for client in server.0.clients.lock().unwrap().values() {
+                    client.output.check_limits();
+                    peak = peak.max(client.output.take_peak());
+                }
+                let mut recent_peaks = server.0.recent_output_peaks.lock().unwrap();
+                recent_peaks.push_back(peak);
+                if recent_peaks.len() > OUTPUT_PEAK_PERIODS {
+                    recent_peaks.pop_front();
+                }
+            }
+        })
+    }
+
+    pub(crate) fn expired_stale_perc(&self) -> f64 {
+        f64::from_bits(self.0.expired_stale_perc.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn master_repl_offset(&self) -> usize {
+        self.0.master_repl_offset.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn next_client_id(&self) -> u64 {
+        self.0.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn pubsub(&self) -> std::sync::MutexGuard<'_, PubSub> {
+        self.0.pubsub.lock().unwrap()
+    }
+
+    pub(crate) fn tracking(&self) -> std::sync::MutexGuard<'_, Tracking> {
+        self.0.tracking.lock().unwrap()
+    }
+
+    pub(crate) fn register_client(&self, info: ClientInfo, push: PushSender, output: Arc<ClientOutput>) {
+        let handle = ClientHandle {
+            push,
+            resp3: false,
+            output,
+            info,
+        };
+        self.0.clients.lock().unwrap().insert(handle.info.id, handle);
+    }
+
+    /// Refreshes the client's state shown by `CLIENT LIST`
+    pub(crate) fn update_client(&self, info: ClientInfo) {
+        if let Some(client) = self.0.clients.lock().unwrap().get_mut(&info.id) {
+            client.info = info;
+        }
+    }
+
+    /// Clients matching the filter, ordered by their IDs
+    pub(crate) fn client_list(&self, filter: &ClientFilter) -> Vec<ClientInfo> {
+        let clients = self.0.clients.lock().unwrap();
+        let mut list: Vec<_> = clients
+            .values()
+            .filter(|client| filter.matches(&client.info))
+            .map(|client| ClientInfo {
+                output_memory: client.output.len(),
+                closing: client.output.is_closing(),
+                ..client.info.clone()
+            })
+            .collect();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Closes clients matching the filter, returning their number
+    pub(crate) fn kill_clients(&self, filter: &ClientFilter) -> usize {
+        let clients = self.0.clients.lock().unwrap();
+        let mut killed = 0;
+        for client in clients.values().filter(|client| filter.matches(&client.info)) {
+            client.output.close();
+            killed += 1;
+        }
+        killed
+    }
+
+    /// Pauses normal clients until the deadline, extending a pause already in progress
+    pub(crate) fn pause_clients(&self, until: Instant, all: bool) {
+        let mut pause = self.0.client_pause.lock().unwrap();
+        *pause = match *pause {
+            Some(current) if current.until > Instant::now() => Some(ClientPause {
+                until: until.max(current.until),
+                all: all || current.all,
+            }),
+            _ => Some(ClientPause { until, all }),
+        };
+    }
+
+    pub(crate) fn unpause_clients(&self) {
+        *self.0.client_pause.lock().unwrap() = None;
+        self.0.client_unpause.notify_waiters();
+    }
+
+    /// End of the pause the command has to wait for, `write` tells whether it's a write command
+    fn paused_until(&self, write: bool) -> Option<Instant> {
+        match *self.0.client_pause.lock().unwrap() {
+            Some(pause) if (pause.all || write) && pause.until > Instant::now() => Some(pause.until),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_paused(&self, write: bool) -> bool {
+        self.paused_until(write).is_some()
+    }
+
+    /// Waits until the command may run, `write` tells whether it's a write command
+    pub(crate) async fn wait_unpaused(&self, write: bool) {
+        loop {
+            // Created before checking the pause, so `CLIENT UNPAUSE` in between isn't missed
+            let unpaused = self.0.client_unpause.notified();
+            let Some(until) = self.paused_until(write) else {
+                return;
+            };
+            tokio::select! {
+                () = unpaused => {}
+                () = tokio::time::sleep_until(until.into()) => {}
+            }
+        }
+    }
+
+    pub(crate) fn connected_clients(&self) -> usize {
+        self.0.clients.lock().unwrap().len()
+    }
+
+    /// Largest output waiting to be written to any of the clients in the last few seconds
+    pub(crate) fn max_output_buffer(&self) -> usize {
+        let current = self.0.clients.lock().unwrap().values().map(|client| client.output.peak()).max().unwrap_or(0);
+        let recent = self.0.recent_output_peaks.lock().unwrap().iter().copied().max().unwrap_or(0);
+        current.max(recent)
+    }
+
+    /// Tracks output of a new client, limited by `client-output-buffer-limit`
+    pub(crate) fn client_output(&self, client: String) -> Arc<ClientOutput> {
+        Arc::new(ClientOutput::new(client, Arc::clone(&self.output_limits)))
+    }
+
+    /// Reserves a slot for a new connection unless `maxclients` is reached, counting the rejected ones
+    pub fn accept_client(&self) -> Option<ClientSlot> {
+        let maxclients = self.maxclients.load(Ordering::Relaxed);
+        // Reserved atomically, so connections accepted at once can't all see a free slot
+        let reserved = self.client_slots.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slots| {
+            (slots < maxclients).then_some(slots + 1)
+        });
+        if reserved.is_err() {
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(ClientSlot(self.clone()))
+    }
+
+    pub(crate) fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Whether client connecting from the address is refused by `protected-mode`
+    pub fn is_protected_from(&self, ip: IpAddr) -> bool {
+        self.protected_mode.load(Ordering::Relaxed) && !ip.to_canonical().is_loopback() && !self.requires_auth()
+    }
+
+    /// Idle time after which clients are closed, `None` when disabled
+    pub(crate) fn client_timeout(&self) -> Option<Duration> {
+        match self.timeout.load(Ordering::Relaxed) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Interval of TCP keepalive probes for new connections, 0 when disabled
+    pub fn tcp_keepalive(&self) -> u64 {
+        self.tcp_keepalive.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn unregister_client(&self, id: u64) {
+        self.0.clients.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn set_client_resp3(&self, id: u64, resp3: bool) {
+        if let Some(client) = self.0.clients.lock().unwrap().get_mut(&id) {
+            client.resp3 = resp3;
+        }
+    }
+
+    pub(crate) fn client_exists(&self, id: u64) -> bool {
+        self.0.clients.lock().unwrap().contains_key(&id)
+    }
+
+    /// Delivers invalidation messages for keys modified since the last call to the tracking clients.
+    ///
+    /// `caller` is the client which modified the keys, so it can be skipped in `NOLOOP` mode.
+    pub(crate) fn send_invalidations(&self, caller: Option<u64>) {
+        let invalidations = self.tracking().take_invalidations(caller);
+        if invalidations.is_empty() {
+            return;
+        }
+        let clients = self.0.clients.lock().unwrap();
+        for invalidation in invalidations {
+            let keys = match invalidation.keys {
+                Some(keys) => RespType::Array(keys.into_iter().map(|key| RespType::BulkString(key.into())).collect()),
+                None => RespType::NullArray,
+            };
+            let target_id = invalidation.redirect.unwrap_or(invalidation.client_id);
+            let Some(target) = clients.get(&target_id) else {
+                if let Some(client) = clients.get(&invalidation.client_id) {
+                    let _ = client.push.send(RespType::Push(VecDeque::from([
+                        RespType::bulk_string_from_bytes(b"tracking-redir-broken"),
+                        RespType::Integer(target_id as i64),
+                    ])));
+                }
+                continue;
+            };
+            // RESP2 clients only get the messages through the pub/sub channel
+            let message = if target.resp3 {
+                RespType::Push(VecDeque::from([RespType::bulk_string_from_bytes(b"invalidate"), keys]))
+            } else if self.pubsub().is_subscribed(SubscriptionKind::Channel, b"__redis__:invalidate", target_id) {
+                RespType::Push(VecDeque::from([
+                    RespType::bulk_string_from_bytes(b"message"),
+                    RespType::bulk_string_from_bytes(b"__redis__:invalidate"),
+                    keys,
+                ]))
+            } else {
+                continue;
+            };
+            let _ = target.push.send(message);
+        }
+    }
+
+    pub(crate) fn acl(&self) -> std::sync::MutexGuard<'_, Acl> {
+        self.0.acl.lock().unwrap()
+    }
+
+    /// Whether new clients have to authenticate before running commands
+    pub(crate) fn requires_auth(&self) -> bool {
+        self.acl().user("default").is_none_or(|user| user.requires_auth())
+    }
+
+    /// Replaces all users by the ones in the ACL file
+    pub(crate) fn load_acl_file(&self) -> Result<(), String> {
+        let path = self.aclfile.as_ref().ok_or_else(|| String::from(ACLFILE_NOT_CONFIGURED))?;
+        let contents = std::fs::read_to_string(path).map_err(|err| format!("Error loading ACL file: {err}"))?;
+        self.acl().load(&contents, &path.to_string_lossy())
+    }
+
+    /// Writes all users to the ACL file, replacing it atomically
+    pub(crate) fn save_acl_file(&self) -> Result<(), String> {
+        let path = self.aclfile.as_ref().ok_or_else(|| String::from(ACLFILE_NOT_CONFIGURED))?;
+        let contents = self.acl().save();
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, contents)
+            .and_then(|_| std::fs::rename(&temp_path, path))
+            .map_err(|err| format!("There was an error trying to save the ACLs. Please check the server logs for more information: {err}"))
+    }
+
+    /// Values of configuration parameters reported by `CONFIG GET`
+    pub(crate) fn config_get(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("port", self.port.to_string()),
+            ("bind", self.bind.iter().map(BindAddr::to_string).collect::<Vec<_>>().join(" ")),
+            ("protected-mode", String::from(if self.protected_mode.load(Ordering::Relaxed) { "yes" } else { "no" })),
+            ("unixsocket", self.unixsocket.as_ref().map(|path| path.to_string_lossy().into_owned()).unwrap_or_default()),
+            ("unixsocketperm", format!("{:o}", self.unixsocketperm.unwrap_or(0))),
+            ("databases", self.databases.to_string()),
+            ("notify-keyspace-events", notify::flags_to_string(self.notifier.flags())),
+            ("requirepass", self.0.requirepass.lock().unwrap().clone().unwrap_or_default()),
+            ("aclfile", self.aclfile.as_ref().map(|path| path.to_string_lossy().into_owned()).unwrap_or_default()),
+            ("masterauth", self.masterauth.clone().unwrap_or_default()),
+            ("client-output-buffer-limit", self.output_limits.lock().unwrap().to_config_string()),
+            ("maxclients", self.maxclients.load(Ordering::Relaxed).to_string()),
+            ("timeout", self.timeout.load(Ordering::Relaxed).to_string()),
+            ("tcp-keepalive", self.tcp_keepalive().to_string()),
+            ("tracking-table-max-keys", self.tracking().max_keys.to_string()),
+        ]
+        .into_iter()
+        .chain(PROTO_LIMITS.into_iter().map(|name| {
+            let limit = self.proto_limit(name).expect("Known protocol limit");
+            (name, limit.load(Ordering::Relaxed).to_string())
         }))
+        .collect()
+    }
+
+    fn proto_limit(&self, name: &str) -> Option<&AtomicUsize> {
+        match name {
+            "proto-max-bulk-len" => Some(&self.proto_limits.max_bulk_len),
+            "proto-max-multibulk-len" => Some(&self.proto_limits.max_multibulk_len),
+            "proto-max-nesting" => Some(&self.proto_limits.max_nesting),
+            "proto-max-line-len" => Some(&self.proto_limits.max_line_len),
+            _ => None,
+        }
+    }
+
+    /// Changes configuration parameter at runtime, `name` must be lowercase
+    pub(crate) fn config_set(&self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "notify-keyspace-events" => {
+                let flags = notify::parse_flags(value).ok_or_else(|| String::from("Invalid event class character"))?;
+                self.notifier.set_flags(flags);
+            }
+            // Already authenticated clients stay authenticated
+            "requirepass" => {
+                let rules = if value.is_empty() {
+                    vec![String::from("nopass")]
+                } else {
+                    vec![String::from("resetpass"), format!(">{value}")]
+                };
+                self.acl().set_user("default", &rules)?;
+                *self.0.requirepass.lock().unwrap() = Some(value.to_owned()).filter(|p| !p.is_empty());
+            }
+            "client-output-buffer-limit" => self.output_limits.lock().unwrap().parse(value)?,
+            "protected-mode" => {
+                let enabled = match value.to_ascii_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(String::from("argument must be 'yes' or 'no'")),
+                };
+                self.protected_mode.store(enabled, Ordering::Relaxed);
+            }
+            // Clients connected above the new limit stay connected
+            "maxclients" => {
+                let value = value
+                    .parse()
+                    .ok()
+                    .filter(|value| *value > 0)
+                    .ok_or_else(|| String::from("argument must be a positive integer"))?;
+                self.maxclients.store(value, Ordering::Relaxed);
+            }
+            "timeout" | "tcp-keepalive" => {
+                let value = value.parse().map_err(|_| String::from("argument couldn't be parsed into an integer"))?;
+                if name == "tcp-keepalive" && value > 0 && !TCP_KEEPALIVE_SUPPORTED {
+                    return Err(String::from("TCP keepalive is not supported on this platform"));
+                }
+                let setting = if name == "timeout" { &self.timeout } else { &self.tcp_keepalive };
+                setting.store(value, Ordering::Relaxed);
+            }
+            // Table above the new limit shrinks once more keys are read
+            "tracking-table-max-keys" => {
+                self.tracking().max_keys = value.parse().map_err(|_| String::from("argument couldn't be parsed into an integer"))?;
+            }
+            name if PROTO_LIMITS.contains(&name) => {
+                let value = value
+                    .parse()
+                    .ok()
+                    .filter(|value| *value > 0)
+                    .ok_or_else(|| String::from("argument must be a positive integer"))?;
+                self.proto_limit(name).expect("Known protocol limit").store(value, Ordering::Relaxed);
+            }
+            "port" | "bind" | "unixsocket" | "unixsocketperm" | "databases" | "masterauth" | "aclfile" => return Err(String::from("can't set immutable config")),
+            _ => return Err(String::from("Unknown option or number of arguments")),
+        }
+        Ok(())
+    }
+
+    /// Locks all databases, indexed by their number
+    pub(crate) async fn data(&self) -> MutexGuard<'_, Vec<Data>> {
+        self.0.data.lock().await
+    }
+
+    pub async fn get(&self, db: usize, key: &[u8]) -> Option<Bytes> {
+        self.0.data.lock().await[db].get(key)
+    }
+    pub async fn set(&self, db: usize, key: &[u8], value: Bytes, expiry: Option<Duration>) -> Option<Bytes> {
+        let old = self.0.data.lock().await[db].set(key, value, expiry);
+        self.send_invalidations(None);
+        old
     }
 
-    pub async fn get(&self, key: Box<[u8]>) -> Option<Box<[u8]>> {
-        self.0.data.lock().await.get(key).await
+    /// Registers new replica, returning stream of write commands to be sent to it, counted towards its `output`
+    pub(crate) fn add_replica(&self, output: &Arc<ClientOutput>) -> OutputReceiver<Arc<[u8]>> {
+        let (tx, rx) = output_channel(output);
+        let mut stream = self.0.replication_stream.lock().unwrap();
+        stream.replicas.push(tx);
+        // The new replica doesn't know which database is selected
+        stream.db = None;
+        rx
     }
-    pub async fn set(
-        &self,
-        key: Box<[u8]>,
-        value: Box<[u8]>,
-        expiry: Option<Duration>,
-    ) -> Option<Box<[u8]>> {
-        self.0.data.lock().await.set(key, value, expiry).await
+
+    pub(crate) fn has_replicas(&self) -> bool {
+        !self.0.replication_stream.lock().unwrap().replicas.is_empty()
+    }
+
+    /// Sends write command executed in database `db` to all replicas.
+    ///
+    /// Must be called while holding the data lock, so replicas apply commands in the same order.
+    pub(crate) fn propagate(&self, db: usize, command: &[u8], mut args: VecDeque<RespType>) {
+        let mut stream = self.0.replication_stream.lock().unwrap();
+        if stream.replicas.is_empty() {
+            return;
+        }
+
+        let mut buf = Vec::new();
+        if stream.db != Some(db) {
+            RespType::Array(VecDeque::from([
+                RespType::bulk_string_from_bytes(b"SELECT"),
+                RespType::bulk_string_from_string(db.to_string()),
+            ]))
+            .encode(&mut buf);
+            stream.db = Some(db);
+        }
+        args.push_front(RespType::bulk_string_from_bytes(command));
+        RespType::Array(args).encode(&mut buf);
+
+        self.0.master_repl_offset.fetch_add(buf.len(), Ordering::Relaxed);
+        let buf: Arc<[u8]> = buf.into();
+        stream.replicas.retain(|replica| replica.send(Arc::clone(&buf)).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::server;
+
+    #[tokio::test]
+    async fn active_expire_skips_replicas() {
+        let master = server(|_| {});
+        let replica = server(|config| config.replication = ReplicationMode::Slave { host: String::from("localhost"), port: 6379 });
+        for server in [&master, &replica] {
+            server.set(0, b"key", Bytes::from_static(b"value"), Some(Duration::from_millis(1))).await;
+            server.spawn_active_expire();
+        }
+        tokio::time::sleep(ACTIVE_EXPIRE_CYCLE_PERIOD * 3).await;
+        assert_eq!(master.data().await[0].expires_count(), 0);
+        assert_eq!(replica.data().await[0].expires_count(), 1);
+    }
+
+    #[test]
+    fn client_slots_are_limited_by_maxclients() {
+        let server = server(|config| config.maxclients = 1);
+        let slot = server.accept_client().unwrap();
+        assert!(server.accept_client().is_none());
+        assert_eq!(server.rejected_connections(), 1);
+
+        // Raising the limit at runtime lets more clients in
+        server.config_set("maxclients", "2").unwrap();
+        let second = server.accept_client().unwrap();
+        assert!(server.accept_client().is_none());
+
+        // Dropped slots are released
+        drop(slot);
+        drop(second);
+        server.config_set("maxclients", "1").unwrap();
+        let _slot = server.accept_client().unwrap();
+        assert!(server.accept_client().is_none());
+        assert_eq!(server.rejected_connections(), 3);
     }
 }
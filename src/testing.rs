@@ -0,0 +1,146 @@
+//! Clients talking to an in-process server through in-memory streams, for tests of the commands
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+
+use crate::resp::{RespReader, RespType, RespWriter};
+use crate::{Config, Connection, PeerAddr, Server};
+
+/// Server with the default configuration changed by `configure`
+pub(crate) fn server(configure: impl FnOnce(&mut Config)) -> Server {
+    let mut config = Config::default();
+    configure(&mut config);
+    Server::new(config).expect("Invalid test configuration")
+}
+
+pub(crate) fn ok() -> RespType {
+    RespType::SimpleString(String::from("OK"))
+}
+
+pub(crate) fn simple(value: &str) -> RespType {
+    RespType::SimpleString(String::from(value))
+}
+
+pub(crate) fn bulk(value: &str) -> RespType {
+    RespType::bulk_string_from_bytes(value.as_bytes())
+}
+
+pub(crate) fn int(value: i64) -> RespType {
+    RespType::Integer(value)
+}
+
+pub(crate) fn array<const N: usize>(items: [RespType; N]) -> RespType {
+    RespType::Array(VecDeque::from(items))
+}
+
+/// Writer counting the writes made to the wrapped one, to check replies are written in batches
+pub(crate) struct CountingWriter<W> {
+    pub inner: W,
+    writes: Arc<AtomicUsize>,
+}
+
+impl<W> CountingWriter<W> {
+    /// Returns the writer and the number of its writes
+    pub(crate) fn new(inner: W) -> (Self, Arc<AtomicUsize>) {
+        let writes = Arc::new(AtomicUsize::new(0));
+        (Self { inner, writes: Arc::clone(&writes) }, writes)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Client connected to the server through an in-memory stream, speaking RESP2
+pub(crate) struct TestClient {
+    reader: RespReader<ReadHalf<DuplexStream>>,
+    writer: RespWriter<WriteHalf<DuplexStream>>,
+    /// Address the server sees the client coming from, unique for each client
+    pub addr: SocketAddr,
+}
+
+impl TestClient {
+    pub(crate) fn connect(server: &Server) -> Self {
+        Self::connect_counting(server).0
+    }
+
+    /// Connects the client, returning also the number of writes the server made to it
+    pub(crate) fn connect_counting(server: &Server) -> (Self, Arc<AtomicUsize>) {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(40000);
+        let addr = SocketAddr::from(([127, 0, 0, 1], NEXT_PORT.fetch_add(1, Ordering::Relaxed)));
+        let (client, conn) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(conn);
+        let laddr = SocketAddr::from(([127, 0, 0, 1], server.port));
+        let (writer, writes) = CountingWriter::new(writer);
+        let conn = Connection::new(reader, writer, addr.into(), PeerAddr::from(laddr), server.clone());
+        tokio::spawn(conn.run_processing_loop());
+
+        let (reader, writer) = tokio::io::split(client);
+        let mut reader = RespReader::new(reader, Arc::clone(&server.proto_limits));
+        reader.set_replies(true);
+        let client = Self {
+            reader,
+            writer: RespWriter::new(writer),
+            addr,
+        };
+        (client, writes)
+    }
+
+    /// Sends the command without waiting for its reply
+    pub(crate) async fn send(&mut self, args: &[&str]) {
+        let command = RespType::Array(args.iter().map(|arg| RespType::bulk_string_from_bytes(arg.as_bytes())).collect());
+        self.writer.write_item(command).await.expect("Failed to send command");
+    }
+
+    /// Sends the commands at once, as a pipeline, without waiting for their replies
+    pub(crate) async fn send_pipeline(&mut self, commands: &[&[&str]]) {
+        for args in commands {
+            let command = RespType::Array(args.iter().map(|arg| RespType::bulk_string_from_bytes(arg.as_bytes())).collect());
+            self.writer.buffer_item(command);
+        }
+        self.writer.flush().await.expect("Failed to send commands");
+    }
+
+    /// Next reply or push, `None` once the server closed the connection
+    pub(crate) async fn read(&mut self) -> Option<RespType> {
+        self.reader.read_item().await.expect("Invalid data from server")
+    }
+
+    pub(crate) async fn cmd(&mut self, args: &[&str]) -> RespType {
+        self.send(args).await;
+        self.read().await.expect("Connection closed by server")
+    }
+
+    /// Runs the command expecting an error, returning its message
+    pub(crate) async fn err(&mut self, args: &[&str]) -> String {
+        match self.cmd(args).await {
+            RespType::SimpleError(message) => message,
+            reply => panic!("Expected error reply to {args:?}, got {reply:?}"),
+        }
+    }
+
+    /// Whether the server closed the connection, the client must not expect any other data
+    pub(crate) async fn is_closed(&mut self) -> bool {
+        matches!(self.reader.read_item().await, Ok(None) | Err(_))
+    }
+}
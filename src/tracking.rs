@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+
+/// Options of client-side caching enabled by `CLIENT TRACKING ON`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrackingOptions {
+    /// Client receiving the invalidation messages instead of the tracking one
+    pub redirect: Option<u64>,
+    /// Broadcast mode, invalidating every key matching one of the prefixes (or all keys)
+    pub bcast: bool,
+    pub prefixes: Vec<Box<[u8]>>,
+    /// Track only keys read right after `CLIENT CACHING yes`
+    pub optin: bool,
+    /// Track all keys except those read right after `CLIENT CACHING no`
+    pub optout: bool,
+    /// Don't invalidate keys modified by the client itself
+    pub noloop: bool,
+}
+
+/// Invalidation message to be delivered to a tracking client
+#[derive(Debug)]
+pub(crate) struct Invalidation {
+    pub client_id: u64,
+    pub redirect: Option<u64>,
+    /// Invalidated keys, `None` when the whole keyspace was flushed
+    pub keys: Option<Vec<Box<[u8]>>>,
+}
+
+/// Invalidation table of client-side caching, shared by all databases
+#[derive(Debug)]
+pub(crate) struct Tracking {
+    clients: HashMap<u64, TrackingOptions>,
+    /// Clients in default mode which read the key since it was last invalidated
+    keys: HashMap<Box<[u8]>, HashSet<u64>>,
+    /// `tracking-table-max-keys`, keys above it are evicted from the table and invalidated, 0 means no limit
+    pub max_keys: usize,
+    /// Keys evicted from the table with the clients to invalidate them for
+    evicted: Vec<(Box<[u8]>, HashSet<u64>)>,
+    /// Keys modified since the invalidations were last collected
+    pending: Vec<Box<[u8]>>,
+    pending_flush: bool,
+}
+
+fn prefixes_overlap(a: &[u8], b: &[u8]) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+impl Tracking {
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            clients: HashMap::new(),
+            keys: HashMap::new(),
+            max_keys,
+            evicted: Vec::new(),
+            pending: Vec::new(),
+            pending_flush: false,
+        }
+    }
+
+    pub fn enable(&mut self, client_id: u64, options: TrackingOptions) -> Result<(), String> {
+        for (i, prefix) in options.prefixes.iter().enumerate() {
+            if let Some(other) = options.prefixes[i + 1..].iter().find(|other| prefixes_overlap(prefix, other)) {
+                return Err(format!(
+                    "Prefix '{}' overlaps with another provided prefix '{}'. Prefixes for a single client must not overlap.",
+                    String::from_utf8_lossy(prefix),
+                    String::from_utf8_lossy(other)
+                ));
+            }
+        }
+        self.clients.insert(client_id, options);
+        Ok(())
+    }
+
+    /// Stops tracking for the client, dropping keys no other client is interested in from the table
+    pub fn disable(&mut self, client_id: u64) {
+        let Some(options) = self.clients.remove(&client_id) else {
+            return;
+        };
+        if !options.bcast {
+            self.keys.retain(|_, clients| {
+                clients.remove(&client_id);
+                !clients.is_empty()
+            });
+        }
+    }
+
+    /// Number of keys in the table
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Remembers keys read by the client in default mode, evicting others if the table grows above `max_keys`
+    pub fn remember(&mut self, client_id: u64, keys: Vec<Box<[u8]>>) {
+        for key in keys {
+            self.keys.entry(key).or_default().insert(client_id);
+        }
+        if self.max_keys > 0 && self.keys.len() > self.max_keys {
+            let excess = self.keys.len() - self.max_keys;
+            let evicted: Vec<_> = self.keys.keys().take(excess).cloned().collect();
+            for key in evicted {
+                let clients = self.keys.remove(&key).expect("Evicted key is in the table");
+                self.evicted.push((key, clients));
+            }
+        }
+    }
+
+    pub fn key_modified(&mut self, key: &[u8]) {
+        if !self.clients.is_empty() {
+            self.pending.push(key.into());
+        }
+    }
+
+    pub fn flushed(&mut self) {
+        if !self.clients.is_empty() {
+            self.pending_flush = true;
+        }
+    }
+
+    /// Collects invalidations for keys modified since the last call, `caller` is the modifying client
+    pub fn take_invalidations(&mut self, caller: Option<u64>) -> Vec<Invalidation> {
+        if self.pending.is_empty() && self.evicted.is_empty() && !self.pending_flush {
+            return Vec::new();
+        }
+        let skipped = |client_id: u64, options: &TrackingOptions| options.noloop && Some(client_id) == caller;
+        if std::mem::take(&mut self.pending_flush) {
+            self.pending.clear();
+            self.evicted.clear();
+            self.keys.clear();
+            return self
+                .clients
+                .iter()
+                .filter(|(&client_id, options)| !skipped(client_id, options))
+                .map(|(&client_id, options)| Invalidation { client_id, redirect: options.redirect, keys: None })
+                .collect();
+        }
+
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_unstable();
+        pending.dedup();
+        let mut invalidated: HashMap<u64, Vec<Box<[u8]>>> = HashMap::new();
+        // Clients can't know about the eviction, so even the one which caused it is notified
+        for (key, clients) in std::mem::take(&mut self.evicted) {
+            for client_id in clients {
+                if self.clients.get(&client_id).is_some_and(|options| !options.bcast) {
+                    invalidated.entry(client_id).or_default().push(key.clone());
+                }
+            }
+        }
+        for key in pending {
+            for client_id in self.keys.remove(&key).into_iter().flatten() {
+                match self.clients.get(&client_id) {
+                    // Client might have switched to broadcast mode in the meantime
+                    Some(options) if !options.bcast && !skipped(client_id, options) => {
+                        invalidated.entry(client_id).or_default().push(key.clone());
+                    }
+                    _ => {}
+                }
+            }
+            for (&client_id, options) in &self.clients {
+                let matches = options.prefixes.is_empty() || options.prefixes.iter().any(|p| key.starts_with(p));
+                if options.bcast && matches && !skipped(client_id, options) {
+                    invalidated.entry(client_id).or_default().push(key.clone());
+                }
+            }
+        }
+        invalidated
+            .into_iter()
+            .map(|(client_id, keys)| Invalidation {
+                client_id,
+                redirect: self.clients[&client_id].redirect,
+                keys: Some(keys),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resp::RespType;
+    use crate::testing::{array, bulk, ok, server, TestClient};
+
+    fn invalidation(keys: &[&str]) -> Option<RespType> {
+        let keys = RespType::Array(keys.iter().map(|key| bulk(key)).collect());
+        Some(array([bulk("message"), bulk("__redis__:invalidate"), keys]))
+    }
+
+    /// Client receiving invalidations of other clients, and its id to redirect them to
+    async fn redirect_target(server: &crate::Server) -> (TestClient, String) {
+        let mut target = TestClient::connect(server);
+        let RespType::Integer(id) = target.cmd(&["CLIENT", "ID"]).await else {
+            panic!("CLIENT ID must reply with an integer");
+        };
+        target.cmd(&["SUBSCRIBE", "__redis__:invalidate"]).await;
+        (target, id.to_string())
+    }
+
+    #[tokio::test]
+    async fn keys_read_are_invalidated_once() {
+        let server = server(|_| {});
+        let (mut target, id) = redirect_target(&server).await;
+        let mut client = TestClient::connect(&server);
+        let mut writer = TestClient::connect(&server);
+        assert_eq!(client.cmd(&["CLIENT", "TRACKING", "ON", "REDIRECT", &id]).await, ok());
+        client.cmd(&["GET", "read"]).await;
+
+        writer.cmd(&["SET", "unread", "value"]).await;
+        writer.cmd(&["SET", "read", "value"]).await;
+        assert_eq!(target.read().await, invalidation(&["read"]));
+        // The key has to be read again to be tracked
+        writer.cmd(&["SET", "read", "again"]).await;
+        client.cmd(&["GET", "read"]).await;
+        client.cmd(&["DEL", "read"]).await;
+        assert_eq!(target.read().await, invalidation(&["read"]));
+    }
+
+    #[tokio::test]
+    async fn broadcast_prefixes_and_noloop() {
+        let server = server(|_| {});
+        let (mut target, id) = redirect_target(&server).await;
+        let mut client = TestClient::connect(&server);
+        let mut writer = TestClient::connect(&server);
+        let tracking = ["CLIENT", "TRACKING", "ON", "REDIRECT", &id, "BCAST", "PREFIX", "user:", "NOLOOP"];
+        assert_eq!(client.cmd(&tracking).await, ok());
+
+        writer.cmd(&["SET", "other", "value"]).await;
+        // Writes of the tracking client itself are skipped with `NOLOOP`
+        client.cmd(&["SET", "user:2", "value"]).await;
+        writer.cmd(&["SET", "user:1", "value"]).await;
+        assert_eq!(target.read().await, invalidation(&["user:1"]));
+
+        assert_eq!(client.cmd(&["CLIENT", "TRACKING", "OFF"]).await, ok());
+        writer.cmd(&["SET", "user:1", "again"]).await;
+        assert_eq!(client.cmd(&["CLIENT", "TRACKING", "ON", "REDIRECT", &id, "BCAST", "PREFIX", "order:"]).await, ok());
+        writer.cmd(&["SET", "order:1", "value"]).await;
+        assert_eq!(target.read().await, invalidation(&["order:1"]));
+    }
+}